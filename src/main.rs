@@ -7,15 +7,23 @@
 mod tests;
 
 use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
 use std::error::Error;
 use std::ffi::{OsStr, OsString};
+use std::io::{BufRead, Write};
+use std::num::NonZeroUsize;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
+use base64::Engine;
 use clap::{
     builder::styling::{AnsiColor, Styles},
-    Parser,
+    ColorChoice, CommandFactory, FromArgMatches, Parser, ValueEnum,
 };
+use regex::bytes::Regex;
 use walkdir::WalkDir;
 
 fn styles() -> Styles {
@@ -28,35 +36,545 @@ fn styles() -> Styles {
 
 /// Command-line argument schema
 #[derive(Parser)]
-#[command(version, about = "Rename files and directories to fit length limits.\n\nBy default, secondary extensions are preserved up to 6 characters; allowable length is adjustable using the -s argument.\n\nSet \"-s 0\" to disable secondary extension preservation.", long_about = None, styles = styles())]
+#[command(version, about = "Rename files and directories to fit length limits.\n\nBy default, secondary extensions are preserved up to 6 characters; allowable length is adjustable using the -s argument.\n\nSet \"-s 0\" (or --no-keep-secondary-ext) to disable secondary extension preservation.\n\n--max-len covers the whole name, but only the stem is shortened to meet it: with --max-len 14, \"a_very_long_file_name.tar.gz\" becomes \"a_very_.tar.gz\", or \"a_very_long.gz\" with --no-keep-secondary-ext, or \"a_very_long_fi\" with --no-keep-primary-ext.", long_about = None, styles = styles())]
 #[derive(Debug)]
 pub struct CliArgs {
     /// Paths to rename (recursively, if directories)
-    #[arg(required = true)]
+    #[arg(required_unless_present_any = [
+        "list_known_exts",
+        "truncate_strategy_help",
+        "stdin_format",
+    ])]
     pub path: Vec<PathBuf>,
 
     /// Length to truncate to. (Default chosen for rclone name encryption)
-    #[arg(long, default_value_t = 140)]
-    pub max_len: usize,
+    #[arg(
+        long,
+        default_value_t = NonZeroUsize::new(140).unwrap(),
+        value_parser = clap::value_parser!(NonZeroUsize)
+    )]
+    pub max_len: NonZeroUsize,
+
+    /// Also limit every component of every path, directory and file names alike, to N bytes.
+    /// The tighter of this and --max-len wins
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(NonZeroUsize))]
+    pub max_len_per_component: Option<NonZeroUsize>,
 
     /// Don't actually rename files. Just print.
-    #[arg(short = 'n', long, action, default_value_t = false)]
+    #[arg(short = 'n', long, visible_alias = "no-rename", action, default_value_t = false)]
     pub dry_run: bool,
 
     /// Maximum length to preserve for secondary extensions
     /// (e.g. 3 for ".tar" in ".tar.gz").
-    /// Set to 0 to disable.
+    /// Set to 0 to disable, even with --extension-whitelist-file.
     #[arg(short = 's', long, default_value_t = 6, value_name = "LEN")]
     pub secondary_ext_len: usize,
 
+    /// Shortcut for --secondary-ext-len 0
+    #[arg(long, action, default_value_t = false, conflicts_with = "secondary_ext_len")]
+    pub no_keep_secondary_ext: bool,
+
+    /// Preserve the primary extension, truncating only what comes before it (the default)
+    #[arg(long, action, default_value_t = false, overrides_with = "no_keep_primary_ext")]
+    pub keep_primary_ext: bool,
+
+    /// Truncate whole file names to --max-len, extensions and all
+    #[arg(
+        long,
+        action,
+        default_value_t = false,
+        overrides_with = "keep_primary_ext",
+        conflicts_with_all = ["stem_only", "extension_only"]
+    )]
+    pub no_keep_primary_ext: bool,
+
+    /// Treat a --secondary-ext-len of at least --max-len as an error instead of reducing it, and
+    /// stop at the first failing --after-rename-hook or --stdin-format collision instead of
+    /// warning
+    #[arg(long, action, default_value_t = false)]
+    pub strict: bool,
+
+    /// Only treat extensions listed in FILE (one per line, no leading dot, '#' for comments) as
+    /// secondary extensions, whatever their length
+    #[arg(long, value_name = "FILE")]
+    pub extension_whitelist_file: Option<PathBuf>,
+
+    /// Don't implicitly whitelist the built-in compound extensions (see --list-known-exts)
+    #[arg(long, action, default_value_t = false, requires = "extension_whitelist_file")]
+    pub no_builtin_known_exts: bool,
+
+    /// Leave files alone unless their primary extension is listed in --extension-whitelist-file
+    /// or ends one of the built-in compound extensions (e.g. "gz")
+    #[arg(long, action, default_value_t = false)]
+    pub limit_to_known_extensions: bool,
+
+    /// How many secondary extensions to preserve at most
+    /// (e.g. 2 for ".tar.bz2" in ".tar.bz2.part")
+    #[arg(long, default_value_t = 1, value_name = "N")]
+    pub max_secondary_exts: u8,
+
+    /// Preserve at most N extension components in all, primary included. Any secondary
+    /// extensions beyond that are left in the stem [default: --max-secondary-exts + 1]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(1..))]
+    pub extension_max_components: Option<u8>,
+
+    /// Also limit stems to N characters (Unicode scalar values, with each invalid UTF-8 byte
+    /// counting as one). The tighter of this and the byte limit from --max-len wins
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(NonZeroUsize))]
+    pub max_stem_utf8_chars: Option<NonZeroUsize>,
+
+    /// Which end of a file's stem to cut (see --truncate-strategy-help)
+    #[arg(long, value_name = "STRATEGY", value_enum, default_value_t = TruncateStrategy::Right)]
+    pub truncate_strategy: TruncateStrategy,
+
     /// Respect word boundaries when truncating
     #[arg(short = 'w', long, action, default_value_t = false)]
     pub word_boundaries: bool,
+
+    /// Leave stems and directories alone and only shorten primary extensions, so that the
+    /// extensions fit in --max-len. Names which are still too long are only warned about
+    #[arg(long, action, default_value_t = false)]
+    pub extension_only: bool,
+
+    /// Only ever shorten stems. Files whose extensions exceed --extension-byte-budget are skipped
+    /// instead of having secondary extensions folded into the stem
+    #[arg(long, action, default_value_t = false, conflicts_with = "extension_only")]
+    pub stem_only: bool,
+
+    /// Maximum bytes the extensions (dots included) may take up. Secondary extensions are
+    /// folded into the stem first, then files are skipped. [default: no limit]
+    #[arg(long, value_name = "N")]
+    pub extension_byte_budget: Option<usize>,
+
+    /// Minimum share of --max-len (0.0 to 1.0) the stem must be left with, enforced the same way
+    /// as --extension-byte-budget
+    #[arg(long, value_name = "F", default_value_t = 0.0, value_parser = parse_ratio)]
+    pub min_extension_stem_ratio: f64,
+
+    /// Ask before each rename. Combined with --dry-run, nothing is renamed unless you say yes.
+    #[arg(short = 'i', long, action, default_value_t = false)]
+    pub interactive: bool,
+
+    /// Skip renames whose new name would still contain non-ASCII bytes, leaving the original
+    #[arg(long, action, default_value_t = false)]
+    pub rename_only_ascii_safe: bool,
+
+    /// Stop after N files and directories have actually been renamed, exiting with status 3
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(NonZeroUsize))]
+    pub max_total_renamed: Option<NonZeroUsize>,
+
+    /// Abort before doing anything if PATH exists (even as a dangling symlink). Can be repeated
+    #[arg(long, value_name = "PATH")]
+    pub fail_if_exists: Vec<PathBuf>,
+
+    /// Before renaming a file, copy it to its original name plus SUFFIX (e.g. ".bak")
+    #[arg(long, value_name = "SUFFIX")]
+    pub backup_suffix: Option<OsString>,
+
+    /// After each rename, run `CMD OLD NEW` through `sh` (with a third `dry_run` argument when
+    /// not actually renaming). Failures are warned about
+    #[arg(long, value_name = "CMD")]
+    pub after_rename_hook: Option<String>,
+
+    /// Before each rename, run `CMD OLD NEW` through `sh` and skip the rename unless it succeeds
+    #[arg(long, value_name = "CMD")]
+    pub before_rename_hook: Option<String>,
+
+    /// How long a hook may run before it's killed and counted as failed
+    #[arg(long, value_name = "MS", default_value_t = 5000)]
+    pub hook_timeout_ms: u64,
+
+    /// Print dry-run renames as bare `OLD<SEP>NEW` lines instead (e.g. '\t').
+    /// Using '\0' also NUL-terminates each pair for `xargs -0`.
+    #[arg(long, value_name = "SEP", value_parser = parse_separator)]
+    pub dry_run_sep: Option<String>,
+
+    /// Show paths in output relative to DIR (or as absolute paths, if outside it)
+    #[arg(long, value_name = "DIR")]
+    pub path_relative_to: Option<PathBuf>,
+
+    /// Print dry-run renames as just `OLD → NEW` file names, without the label or quoting
+    #[arg(long, action, default_value_t = false, requires = "dry_run")]
+    pub dry_run_compact: bool,
+
+    /// Before truncating a stem, strip a suffix matching this regular expression from it
+    #[arg(long, value_name = "REGEX", value_parser = parse_trailing_pattern)]
+    pub trim_trailing_pattern: Option<Regex>,
+
+    /// Shortcut for --trim-trailing-pattern '[_\-]?[0-9]+'
+    #[arg(long, action, default_value_t = false, conflicts_with = "trim_trailing_pattern")]
+    pub trim_trailing_numbers: bool,
+
+    /// Don't rename anything. Print each rename as an `mv` line with all non-ASCII characters
+    /// written as `\uXXXX`/`\UXXXXXXXX` (and invalid UTF-8 as `\xHH`) instead
+    #[arg(long, action, default_value_t = false)]
+    pub simulate_rename: bool,
+
+    /// Explain decisions on stderr (-v: over-long names left alone by
+    /// --limit-to-known-extensions; -vv: file groups and extensions too, as with --verbose-groups
+    /// and --verbose-extensions; -vvv: per-group stem budget calculations too)
+    #[arg(short = 'v', long, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Before truncating anything, print which files were grouped under a shared stem to stderr
+    #[arg(long, action, default_value_t = false)]
+    pub verbose_groups: bool,
+
+    /// Print how each file's name was split into stem and extensions to stderr
+    #[arg(long, action, default_value_t = false)]
+    pub verbose_extensions: bool,
+
+    /// How to print file names [default: quoted, with Rust-style escapes]
+    #[arg(long, value_name = "ENCODING", value_enum)]
+    pub output_encoding: Option<OutputEncoding>,
+
+    /// When to use colors in help and error messages
+    #[arg(long, value_name = "WHEN", value_enum, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Shortcut for --color=never. (The NO_COLOR environment variable is also respected)
+    #[arg(long, action, default_value_t = false)]
+    pub no_color: bool,
+
+    /// Instead of walking PATHs, apply the exact renames read from stdin in this format
+    #[arg(long, value_name = "FORMAT", value_enum, conflicts_with_all = ["path", "interactive"])]
+    pub stdin_format: Option<StdinFormat>,
+
+    /// When done (or stopped), print a table of how many of each extension there were and how
+    /// many were renamed. Directories are listed as "(directory)"
+    #[arg(long, action, default_value_t = false)]
+    pub summarize_by_extension: bool,
+
+    /// Just print how many names exceed --max-len, without planning or renaming anything
+    #[arg(long, action, default_value_t = false)]
+    pub count_only: bool,
+
+    /// Print the built-in list of known compound extensions and exit
+    #[arg(long, action, default_value_t = false)]
+    pub list_known_exts: bool,
+
+    /// Explain each --truncate-strategy with examples and exit
+    #[arg(long, action, default_value_t = false)]
+    pub truncate_strategy_help: bool,
+}
+
+/// Representations available for file names in output
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputEncoding {
+    /// As UTF-8, with invalid bytes replaced by U+FFFD
+    Utf8,
+    /// As ASCII, with `\xHH` escapes for everything else
+    Escaped,
+    /// The whole name, hex-encoded
+    Hex,
+    /// The whole name, base64-encoded
+    Base64,
+}
+
+/// Formats `--stdin-format` can read renames in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum StdinFormat {
+    /// One `OLD<TAB>NEW` pair per line, as raw bytes
+    Pairs,
+}
+
+/// Where to cut a stem that's over its budget
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TruncateStrategy {
+    /// Keep the start of the stem
+    Right,
+    /// Keep the end of the stem
+    Left,
+    /// Keep the start and end of the stem, cutting from the middle
+    Middle,
+}
+
+impl TruncateStrategy {
+    /// Cut `r_stem` down to `max_stem_bytes` and, if given, `max_chars` characters from wherever
+    /// this strategy says, never splitting a UTF-8 sequence. `word_boundaries` only applies to
+    /// `Right`.
+    ///
+    /// A hidden file's leading dot is always kept, whichever end is being cut.
+    pub fn apply(
+        self,
+        r_stem: OsString,
+        max_stem_bytes: usize,
+        max_chars: Option<usize>,
+        word_boundaries: bool,
+    ) -> OsString {
+        let bytes = r_stem.as_bytes();
+        let (dot, rest, head, tail) = self.kept_parts(bytes, max_stem_bytes, max_chars);
+        if dot + head + tail == bytes.len() {
+            return r_stem;
+        }
+        match self {
+            TruncateStrategy::Right => truncate_stem(r_stem, dot + head, word_boundaries),
+            _ => {
+                let kept = [&bytes[..dot], &rest[..head], &rest[rest.len() - tail..]].concat();
+                OsStr::from_bytes(&kept).into()
+            },
+        }
+    }
+
+    /// How many bytes of `r_stem` survive `apply`, before any `--word-boundaries` backtracking
+    pub fn kept_len(self, r_stem: &[u8], max_stem_bytes: usize, max_chars: Option<usize>) -> usize {
+        let (dot, _, head, tail) = self.kept_parts(r_stem, max_stem_bytes, max_chars);
+        dot + head + tail
+    }
+
+    /// Split `bytes` into a pinned leading dot (0 or 1 bytes) and the rest, and say how many bytes
+    /// from the start and the end of the rest survive both limits
+    fn kept_parts(
+        self,
+        bytes: &[u8],
+        max_stem_bytes: usize,
+        max_chars: Option<usize>,
+    ) -> (usize, &[u8], usize, usize) {
+        let max_chars = max_chars.unwrap_or(usize::MAX);
+        let dot = usize::from(bytes.starts_with(b".") && max_stem_bytes > 0 && max_chars > 0);
+        let (rest, max_bytes, max_chars) = (&bytes[dot..], max_stem_bytes - dot, max_chars - dot);
+        if rest.len() <= max_bytes && char_prefix_len(rest, max_chars) == rest.len() {
+            return (dot, rest, rest.len(), 0);
+        }
+        let (head, tail) = match self {
+            TruncateStrategy::Right => (utf8_prefix(rest, max_bytes, max_chars).len(), 0),
+            TruncateStrategy::Left => (0, utf8_suffix(rest, max_bytes, max_chars).len()),
+            TruncateStrategy::Middle => {
+                let head = utf8_prefix(rest, max_bytes.div_ceil(2), max_chars.div_ceil(2)).len();
+                let tail = utf8_suffix(&rest[head..], max_bytes - head, max_chars / 2).len();
+                (head, tail)
+            },
+        };
+        (dot, rest, head, tail)
+    }
+}
+
+/// What `--truncate-strategy-help` prints
+pub const TRUNCATE_STRATEGY_HELP: &str = "\
+Strategies for --truncate-strategy, which only applies to file stems. Directory names are
+always cut from the right, and extensions are preserved as usual.
+
+  right (default)  Keep the start of the stem, cutting off its end. The only strategy that
+                   honours --word-boundaries.
+                       a_very_long_file_name.txt -> a_very_lon.txt   (--max-len 14)
+  left             Keep the end of the stem, cutting off its start. Useful when names differ
+                   mostly at the end, like numbered episodes or dated logs.
+                       a_very_long_file_name.txt -> _file_name.txt   (--max-len 14)
+  middle           Keep the start and end of the stem, cutting from the middle.
+                       a_very_long_file_name.txt -> a_ver_name.txt   (--max-len 14)
+
+Whichever strategy is used, cuts never split a UTF-8 character, and every file sharing a stem
+is cut the same way.";
+
+/// Compound extensions (without the leading dot) which are common enough to be built in
+pub static KNOWN_COMPOUND_EXTENSIONS: &[&str] =
+    &["tar.gz", "tar.bz2", "tar.xz", "tar.zst", "tar.lz", "tar.lz4", "tar.lzma", "tar.Z"];
+
+/// `KNOWN_COMPOUND_EXTENSIONS` as a set, for lookups and for augmenting with extra entries
+pub fn known_compound_extensions() -> &'static HashSet<&'static str> {
+    static KNOWN: OnceLock<HashSet<&'static str>> = OnceLock::new();
+    KNOWN.get_or_init(|| KNOWN_COMPOUND_EXTENSIONS.iter().copied().collect())
+}
+
+/// The pattern `--trim-trailing-numbers` is shorthand for
+pub const TRAILING_NUMBERS_PATTERN: &str = r"[_\-]?[0-9]+";
+
+/// Compile a `--trim-trailing-pattern` value, anchored so it can only ever match a suffix
+pub fn parse_trailing_pattern(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("(?:{})$", pattern))
+}
+
+/// Unescape the `\t`, `\n`, `\0` and `\\` sequences a shell would pass through literally
+pub fn parse_separator(sep: &str) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(sep.len());
+    let mut chars = sep.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('0') => unescaped.push('\0'),
+            Some('\\') => unescaped.push('\\'),
+            Some(other) => return Err(format!("unsupported escape sequence '\\{}'", other)),
+            None => return Err("trailing backslash".to_owned()),
+        }
+    }
+    Ok(unescaped)
+}
+
+/// Parse a ratio in the inclusive range 0.0 to 1.0
+pub fn parse_ratio(ratio: &str) -> Result<f64, String> {
+    match ratio.parse::<f64>() {
+        Ok(value) if (0.0..=1.0).contains(&value) => Ok(value),
+        Ok(value) => Err(format!("{} is not between 0.0 and 1.0", value)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Work out `--color`/`--no-color` ahead of parsing, so it also applies to clap's own output
+pub fn color_choice<I: IntoIterator<Item = OsString>>(args: I) -> ColorChoice {
+    let mut choice = ColorChoice::Auto;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let value = match arg.to_str() {
+            Some("--") => break,
+            Some("--no-color") => {
+                choice = ColorChoice::Never;
+                continue;
+            },
+            Some("--color") => args.next(),
+            Some(arg) => match arg.strip_prefix("--color=") {
+                Some(value) => Some(value.into()),
+                None => continue,
+            },
+            None => continue,
+        };
+        match value.as_ref().and_then(|v| v.to_str()) {
+            Some("always") => choice = ColorChoice::Always,
+            Some("auto") => choice = ColorChoice::Auto,
+            Some("never") => choice = ColorChoice::Never,
+            _ => {}, // Left for clap to report
+        }
+    }
+
+    // https://no-color.org/: Only an empty value doesn't count, and explicit flags take priority
+    if choice == ColorChoice::Auto && std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+        choice = ColorChoice::Never;
+    }
+    choice
 }
 
+impl CliArgs {
+    /// Fold `--max-len-per-component` into `--max-len`, since both limit each name on its own
+    pub fn apply_max_len_per_component(&mut self) {
+        if let Some(limit) = self.max_len_per_component {
+            self.max_len = self.max_len.min(limit);
+        }
+    }
+
+    /// Rein in a `--secondary-ext-len` at least as long as `--max-len` by reducing it to at most
+    /// half of `--max-len`. Returns the warning to print, if any.
+    ///
+    /// Whatever this reduces it to passes the secondary extension check in `validate`, which is
+    /// still what catches lengths that are shorter than `--max-len` but don't fit.
+    pub fn clamp_secondary_ext_len(&mut self) -> Result<Option<String>, String> {
+        if self.secondary_ext_len < self.max_len.get() {
+            return Ok(None);
+        }
+        let msg = format!(
+            "secondary_ext_len ({}) leaves no room for a stem within max_len ({})",
+            self.secondary_ext_len, self.max_len
+        );
+        if self.strict {
+            return Err(format!("{}.", msg));
+        }
+        // A stem byte and the two dots on either side of the secondary extension
+        let max_fitting = self.max_len.get().saturating_sub(2 + 1);
+        self.secondary_ext_len = (self.max_len.get() / 2).min(max_fitting);
+        Ok(Some(format!("{}. Reducing it to {}.", msg, self.secondary_ext_len)))
+    }
+
+    /// Catch settings which can't produce any valid output before touching the filesystem
+    pub fn validate(&self) -> Result<(), String> {
+        // A stem byte, plus the dots and contents of the longest allowable secondary extension
+        let min_len = self.secondary_ext_len + 2 + 1;
+        if self.secondary_ext_len > 0 && self.max_len.get() < min_len {
+            return Err(format!(
+                "max_len ({}) is too small to hold any secondary extension of max length \
+                 secondary_ext_len ({}). Either increase max_len or decrease secondary_ext_len.",
+                self.max_len, self.secondary_ext_len
+            ));
+        }
+        Ok(())
+    }
+
+    /// How many secondary extensions to peel back, after `--extension-max-components` has set
+    /// aside one component for the primary extension
+    pub fn secondary_ext_levels(&self) -> u8 {
+        match self.extension_max_components {
+            Some(components) => self.max_secondary_exts.min(components - 1),
+            None => self.max_secondary_exts,
+        }
+    }
+
+    /// The primary extensions (lowercase, without the dot) `--limit-to-known-extensions` allows,
+    /// or `None` if any file may be renamed
+    pub fn known_primary_exts(&self) -> Result<Option<HashSet<OsString>>, Box<dyn Error>> {
+        if !self.limit_to_known_extensions {
+            return Ok(None);
+        }
+        let mut exts = match &self.extension_whitelist_file {
+            Some(path) => load_extension_whitelist(path, false)?,
+            None => HashSet::new(),
+        };
+        if !self.no_builtin_known_exts {
+            for compound in KNOWN_COMPOUND_EXTENSIONS {
+                let primary = compound.rsplit_once('.').map_or(*compound, |(_, primary)| primary);
+                exts.insert(OsString::from(primary.to_ascii_lowercase()));
+            }
+        }
+        Ok(Some(exts))
+    }
+
+    /// Build the secondary extension policy, reading `--extension-whitelist-file` if given
+    pub fn secondary_ext_policy(&self) -> Result<SecondaryExtPolicy, Box<dyn Error>> {
+        Ok(match &self.extension_whitelist_file {
+            Some(path) if self.secondary_ext_len > 0 => SecondaryExtPolicy::Whitelist(
+                load_extension_whitelist(path, !self.no_builtin_known_exts)?,
+            ),
+            _ => SecondaryExtPolicy::MaxLen(self.secondary_ext_len),
+        })
+    }
+
+    /// The tighter of `--extension-byte-budget` and `--min-extension-stem-ratio`
+    pub fn ext_budget(&self) -> usize {
+        let mut budget = self.extension_byte_budget.unwrap_or(usize::MAX);
+        if self.min_extension_stem_ratio > 0.0 {
+            let min_stem =
+                (self.min_extension_stem_ratio * self.max_len.get() as f64).ceil() as usize;
+            budget = budget.min(self.max_len.get().saturating_sub(min_stem));
+        }
+        budget
+    }
+
+    /// How many bytes a group of `files` sharing a stem can spend on it within `--max-len`
+    pub fn stem_budget(&self, files: &[(PathBuf, FileParts)]) -> usize {
+        calculate_max_stem_bytes(files, self.max_len.get())
+    }
+
+    /// How many bytes of `r_stem` survive truncation to `max_stem_bytes`, once
+    /// `--max-stem-utf8-chars` and `--truncate-strategy` are taken into account
+    pub fn kept_stem_len(&self, r_stem: &OsStr, max_stem_bytes: usize) -> usize {
+        let max_chars = self.max_stem_utf8_chars.map(NonZeroUsize::get);
+        self.truncate_strategy.kept_len(r_stem.as_bytes(), max_stem_bytes, max_chars)
+    }
+
+    /// `--hook-timeout-ms` as a `Duration`
+    pub fn hook_timeout(&self) -> Duration {
+        Duration::from_millis(self.hook_timeout_ms)
+    }
+
+    /// The effective trailing pattern, taking `--trim-trailing-numbers` into account
+    pub fn trailing_pattern(&self) -> Option<Regex> {
+        self.trim_trailing_pattern.clone().or_else(|| {
+            self.trim_trailing_numbers.then(|| {
+                parse_trailing_pattern(TRAILING_NUMBERS_PATTERN).expect("valid built-in pattern")
+            })
+        })
+    }
+}
+
+/// Split a file name into its stem and (primary) extension
+///
+/// A leading dot marks a hidden file rather than an extension, so it always stays part of the
+/// stem. Every `TruncateStrategy` keeps a stem's leading dot, so this guarantees that truncating
+/// a hidden file's name can never unhide it.
 pub fn split_stem_ext(name: &OsStr) -> (&OsStr, Option<&OsStr>) {
     let bytes = name.as_bytes();
-    if let Some(last_dot) = bytes.iter().rposition(|&b| b == b'.') {
+    if let Some(last_dot) = bytes.iter().rposition(|&b| b == b'.').filter(|&pos| pos > 0) {
         // Only consider extension if no path separators in stem
         if !bytes[..last_dot].contains(&b'/') && !bytes[..last_dot].contains(&b'\\') {
             let stem = OsStr::from_bytes(&bytes[..last_dot]);
@@ -70,44 +588,166 @@ pub fn split_stem_ext(name: &OsStr) -> (&OsStr, Option<&OsStr>) {
     }
 }
 
-pub fn split_rstem_ext(
-    name: &OsStr,
-    secondary_ext_len: usize,
-) -> (OsString, Option<OsString>, Option<OsString>) {
+/// A file name, split into the parts truncation treats differently
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileParts {
+    /// Everything to the left of the preserved extensions
+    pub rstem: OsString,
+    /// Secondary extensions in the order they appear (`["tar", "bz2"]` for `a.tar.bz2.part`)
+    pub secondary_exts: Vec<OsString>,
+    pub primary_ext: Option<OsString>,
+}
+
+impl FileParts {
+    /// Whether the primary extension is in `known` (compared ASCII-lowercase)
+    pub fn has_primary_ext_in(&self, known: &HashSet<OsString>) -> bool {
+        self.primary_ext.as_ref().is_some_and(|ext| {
+            known.contains(OsStr::from_bytes(&ext.as_bytes().to_ascii_lowercase()))
+        })
+    }
+
+    /// Bytes taken up by the extensions, including the dot in front of each
+    pub fn ext_bytes(&self) -> usize {
+        self.primary_ext.iter().chain(&self.secondary_exts).map(|e| e.as_bytes().len() + 1).sum()
+    }
+}
+
+/// How to decide whether a dotted component of a stem is a secondary extension
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SecondaryExtPolicy {
+    /// Anything up to this many bytes long (so 0 disables secondary extensions)
+    MaxLen(usize),
+    /// Only these extensions (lowercase, without the dot), whatever their length
+    Whitelist(HashSet<OsString>),
+}
+
+impl SecondaryExtPolicy {
+    pub fn accepts(&self, ext: &[u8]) -> bool {
+        match self {
+            SecondaryExtPolicy::MaxLen(len) => ext.len() <= *len,
+            SecondaryExtPolicy::Whitelist(exts) => {
+                exts.contains(OsStr::from_bytes(&ext.to_ascii_lowercase()))
+            },
+        }
+    }
+}
+
+/// Read an extension whitelist: one extension per line, without the leading dot, with lines
+/// starting with `#` ignored. Unless `builtin` is false, the secondary parts of
+/// `KNOWN_COMPOUND_EXTENSIONS` (e.g. "tar") are included too.
+pub fn load_extension_whitelist(
+    path: &Path,
+    builtin: bool,
+) -> Result<HashSet<OsString>, Box<dyn Error>> {
+    let contents =
+        std::fs::read(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+
+    let mut exts: HashSet<OsString> = contents
+        .split(|&b| b == b'\n')
+        .map(|line| line.trim_ascii())
+        .filter(|line| !line.is_empty() && !line.starts_with(b"#"))
+        .map(|ext| OsStr::from_bytes(&ext.to_ascii_lowercase()).to_os_string())
+        .collect();
+    if builtin {
+        for compound in KNOWN_COMPOUND_EXTENSIONS {
+            if let Some((secondary, _)) = compound.rsplit_once('.') {
+                exts.extend(secondary.split('.').map(|e| OsString::from(e.to_ascii_lowercase())));
+            }
+        }
+    }
+    Ok(exts)
+}
+
+/// Split off the primary extension, then peel back up to `max_levels` secondary extensions
+pub fn split_rstem_ext(name: &OsStr, policy: &SecondaryExtPolicy, max_levels: u8) -> FileParts {
     let (stem, primary_ext) = split_stem_ext(name);
 
-    if secondary_ext_len == 0 {
-        return (stem.to_os_string(), None, primary_ext.map(|s| s.to_os_string()));
+    let mut rstem = stem.as_bytes();
+    let mut secondary_exts = Vec::new();
+    while secondary_exts.len() < usize::from(max_levels) {
+        match rstem.iter().rposition(|&b| b == b'.').filter(|&pos| pos > 0) {
+            Some(dot) if policy.accepts(&rstem[dot + 1..]) => {
+                secondary_exts.push(OsStr::from_bytes(&rstem[dot + 1..]).to_os_string());
+                rstem = &rstem[..dot];
+            },
+            _ => break,
+        }
     }
+    secondary_exts.reverse();
 
-    let stem_bytes = stem.as_bytes();
-    if let Some(second_dot) = stem_bytes.iter().rposition(|&b| b == b'.') {
-        let ext_part = &stem_bytes[second_dot + 1..];
+    FileParts {
+        rstem: OsStr::from_bytes(rstem).to_os_string(),
+        secondary_exts,
+        primary_ext: primary_ext.map(|s| s.to_os_string()),
+    }
+}
 
-        if ext_part.len() <= secondary_ext_len {
-            let rstem = OsStr::from_bytes(&stem_bytes[..second_dot]);
-            let secondary_ext = OsStr::from_bytes(ext_part);
-            return (
-                rstem.to_os_string(),
-                Some(secondary_ext.to_os_string()),
-                primary_ext.map(|s| s.to_os_string()),
-            );
+/// Length of the longest prefix of `bytes` that's valid UTF-8, found in a single pass
+///
+/// Cutting there is what keeps truncation from splitting a code point. (For names which weren't
+/// valid UTF-8 to begin with, it also drops everything from the first invalid byte onward.)
+pub fn valid_utf8_prefix_len(bytes: &[u8]) -> usize {
+    std::str::from_utf8(bytes).map_or_else(|e| e.valid_up_to(), |_| bytes.len())
+}
+
+/// The longest prefix of `bytes` that's at most `max` bytes and `max_chars` characters, and
+/// valid UTF-8
+fn utf8_prefix(bytes: &[u8], max: usize, max_chars: usize) -> &[u8] {
+    let prefix = &bytes[..bytes.len().min(max).min(char_prefix_len(bytes, max_chars))];
+    &prefix[..valid_utf8_prefix_len(prefix)]
+}
+
+/// The longest suffix of `bytes` that's at most `max` bytes and `max_chars` characters, and
+/// doesn't start partway through a UTF-8 sequence
+fn utf8_suffix(bytes: &[u8], max: usize, max_chars: usize) -> &[u8] {
+    let suffix = &bytes[bytes.len().saturating_sub(max).max(char_suffix_start(bytes, max_chars))..];
+    // A sequence has at most three continuation bytes after its leading byte
+    let partial = suffix.iter().take(3).take_while(|&&b| b & 0xC0 == 0x80).count();
+    &suffix[partial..]
+}
+
+/// Length in bytes of the first `chars` characters of `bytes`, counting each byte that isn't
+/// part of a valid UTF-8 sequence as a character of its own
+pub fn char_prefix_len(bytes: &[u8], chars: usize) -> usize {
+    let mut remaining = chars;
+    let mut len = 0;
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if remaining == 0 {
+                return len;
+            }
+            remaining -= 1;
+            len += c.len_utf8();
         }
+        let invalid = chunk.invalid().len().min(remaining);
+        remaining -= invalid;
+        len += invalid;
     }
+    len
+}
 
-    (stem.to_os_string(), None, primary_ext.map(|s| s.to_os_string()))
+/// Where the last `chars` characters of `bytes` start, counting characters the same way as
+/// `char_prefix_len`
+fn char_suffix_start(bytes: &[u8], chars: usize) -> usize {
+    let total: usize = bytes
+        .utf8_chunks()
+        .map(|chunk| chunk.valid().chars().count() + chunk.invalid().len())
+        .sum();
+    char_prefix_len(bytes, total.saturating_sub(chars))
 }
 
 /// Figure out the new name when truncating a path
 ///
+/// `is_dir` comes from the caller (usually `DirEntry::file_type`) so this never has to `stat`.
+///
 /// **NOTE:** Handling of non-UTF8-able path is currently hacky
 pub fn trunc_path(
     path: &Path,
+    is_dir: bool,
     max_len: usize,
     secondary_ext_len: usize,
     word_boundaries: bool,
 ) -> Result<Cow<'_, Path>, Box<dyn Error>> {
-    let is_dir = path.is_dir();
     let fname = match path.file_name() {
         Some(os_str) => os_str,
         None => return Ok(Cow::from(path)),
@@ -117,12 +757,10 @@ pub fn trunc_path(
     if is_dir {
         let stem_bytes = fname.as_bytes();
         let max_stem_bytes = max_len;
-        let mut truncated_bytes = &stem_bytes[..stem_bytes.len().min(max_stem_bytes)];
+        let truncated_bytes = &stem_bytes[..stem_bytes.len().min(max_stem_bytes)];
 
         // Add UTF-8 boundary check for directories
-        while std::str::from_utf8(truncated_bytes).is_err() {
-            truncated_bytes = &truncated_bytes[..truncated_bytes.len().saturating_sub(1)];
-        }
+        let truncated_bytes = &truncated_bytes[..valid_utf8_prefix_len(truncated_bytes)];
         let mut truncated = OsStr::from_bytes(truncated_bytes).to_os_string();
 
         // Preserve whole words where possible
@@ -136,6 +774,10 @@ pub fn trunc_path(
             }
         }
 
+        if truncated == fname {
+            return Ok(Cow::Borrowed(path));
+        }
+
         let parent = path.parent().unwrap_or_else(|| Path::new(""));
         let new_path = parent.join(truncated);
         return Ok(Cow::from(new_path));
@@ -157,20 +799,21 @@ pub fn trunc_path(
 
     if secondary_ext_len > 0 {
         if let Ok(fname_str) = std::str::from_utf8(raw) {
-            // Split into main part and main extension
+            // Split into main part and main extension (a leading dot is a hidden file's, not an
+            // extension's)
             let (main_part, main_ext) = match fname_str.rsplit_once('.') {
-                Some((mp, me)) => (mp, me),
-                None => (fname_str, ""),
+                Some((mp, me)) if !mp.is_empty() => (mp, Some(me)),
+                _ => (fname_str, None),
             };
 
             // Check for valid secondary extension
             let (stem, secondary_ext) = match main_part.rsplit_once('.') {
-                Some((s, se)) if se.len() <= secondary_ext_len => (s, Some(se)),
+                Some((s, se)) if !s.is_empty() && se.len() <= secondary_ext_len => (s, Some(se)),
                 _ => (main_part, None),
             };
 
             // Calculate total length needed for extensions in BYTES
-            let ext_bytes = main_ext.len() + 1 +  // main extension + dot
+            let ext_bytes = main_ext.map(|me| me.len() + 1).unwrap_or(0) +  // main extension + dot
                 secondary_ext.map(|se| se.len() + 1).unwrap_or(0); // secondary extension + dot
 
             // Calculate available space for stem
@@ -178,12 +821,10 @@ pub fn trunc_path(
 
             // Truncate stem from right without splitting words
             let stem_bytes = stem.as_bytes();
-            let mut truncated_bytes = &stem_bytes[..stem_bytes.len().min(max_stem_bytes)];
+            let truncated_bytes = &stem_bytes[..stem_bytes.len().min(max_stem_bytes)];
 
             // Preserve UTF-8 validity
-            while std::str::from_utf8(truncated_bytes).is_err() {
-                truncated_bytes = &truncated_bytes[..truncated_bytes.len() - 1];
-            }
+            let truncated_bytes = &truncated_bytes[..valid_utf8_prefix_len(truncated_bytes)];
 
             let mut truncated_stem =
                 String::from_utf8(truncated_bytes.to_vec()).unwrap_or_else(|_| String::new());
@@ -206,8 +847,15 @@ pub fn trunc_path(
                 new_fname.push('.');
                 new_fname.push_str(se);
             }
-            new_fname.push('.');
-            new_fname.push_str(main_ext);
+            if let Some(me) = main_ext {
+                new_fname.push('.');
+                new_fname.push_str(me);
+            }
+
+            // Don't allocate a new path just to say nothing changed
+            if new_fname == fname_str {
+                return Ok(Cow::Borrowed(path));
+            }
 
             let parent = path.parent().unwrap_or_else(|| Path::new(""));
             let new_path = parent.join(new_fname);
@@ -215,18 +863,7 @@ pub fn trunc_path(
         }
     }
 
-    let new_fname_len = if std::str::from_utf8(raw).is_ok() {
-        match std::str::from_utf8(raw_trunc) {
-            Ok(_) => raw_trunc.len(),
-            Err(e) => e.valid_up_to(),
-        }
-    } else {
-        let mut valid_len = raw_trunc.len();
-        while valid_len > 0 && std::str::from_utf8(&raw_trunc[..valid_len]).is_err() {
-            valid_len -= 1;
-        }
-        valid_len
-    };
+    let new_fname_len = valid_utf8_prefix_len(raw_trunc);
 
     let path_raw = path.as_os_str().as_bytes();
     let mut new_len = path_raw.len() - (raw.len() - new_fname_len);
@@ -244,47 +881,232 @@ pub fn trunc_path(
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let args = CliArgs::parse();
-    process_files(&args)?;
-    process_directories(&args)?;
+    let mut command = CliArgs::command().color(color_choice(std::env::args_os()));
+    let mut args =
+        CliArgs::from_arg_matches(&command.get_matches_mut()).unwrap_or_else(|e| e.exit());
+    if args.no_keep_secondary_ext {
+        args.secondary_ext_len = 0;
+    }
+    args.apply_max_len_per_component();
+    match args.clamp_secondary_ext_len() {
+        Ok(Some(warning)) => eprintln!("Warning: {}", warning),
+        Ok(None) => {},
+        Err(msg) => command.error(clap::error::ErrorKind::ValueValidation, msg).exit(),
+    }
+    if let Err(msg) = args.validate() {
+        command.error(clap::error::ErrorKind::ValueValidation, msg).exit();
+    }
+    ensure_absent(&args.fail_if_exists)?;
+    if args.truncate_strategy_help {
+        println!("{}", TRUNCATE_STRATEGY_HELP);
+        return Ok(());
+    }
+    if args.list_known_exts {
+        let mut exts: Vec<_> = known_compound_extensions().iter().collect();
+        exts.sort_unstable();
+        for ext in exts {
+            println!(".{}", ext);
+        }
+        return Ok(());
+    }
+    if args.count_only {
+        println!("{}", estimate_renames(&args.path, args.max_len.get())?);
+        return Ok(());
+    }
+
+    let mut state = RunState::default();
+    let result = run(&args, &mut state);
+    if args.summarize_by_extension {
+        print!("{}", format_extension_summary(&state.ext_stats));
+    }
+    match result {
+        Err(e) if e.is::<RenameLimitReached>() => {
+            eprintln!("{}", e);
+            std::process::exit(3);
+        },
+        result => result,
+    }
+}
+
+/// Do whichever kind of renaming was asked for
+fn run(args: &CliArgs, state: &mut RunState) -> Result<(), Box<dyn Error>> {
+    if let Some(StdinFormat::Pairs) = args.stdin_format {
+        let pairs = parse_pairs(std::io::stdin().lock())?;
+        return process_pairs(args, state, &pairs);
+    }
+    if args.extension_only {
+        return process_extensions(args, state);
+    }
+
+    process_files(args, state)?;
+    process_directories(args, state)?;
     Ok(())
 }
 
-pub fn process_files(args: &CliArgs) -> Result<(), Box<dyn Error>> {
-    let mut file_groups = std::collections::HashMap::new();
+/// What a run has done so far, carried from one rename to the next
+#[derive(Debug, Default)]
+pub struct RunState {
+    /// How many renames have been applied, for `--max-total-renamed`
+    pub renamed: usize,
+    /// Tallies for `--summarize-by-extension`, keyed by `extension_key`
+    pub ext_stats: BTreeMap<String, ExtensionStats>,
+    /// Whether a path outside `--path-relative-to` has been warned about yet
+    pub warned_outside_base: bool,
+}
+
+impl RunState {
+    /// Count a file or directory that was considered for renaming under `key`
+    pub fn count_seen(&mut self, key: String) {
+        self.ext_stats.entry(key).or_default().total += 1;
+    }
+
+    /// Count a rename from `path` to `new_path` under `key`
+    pub fn count_renamed(&mut self, key: String, path: &Path, new_path: &Path) {
+        let old_len = path.file_name().unwrap_or_default().len();
+        let new_len = new_path.file_name().unwrap_or_default().len();
+        let stats = self.ext_stats.entry(key).or_default();
+        stats.renamed += 1;
+        stats.bytes_saved += old_len.saturating_sub(new_len);
+    }
+}
+
+/// Raised once `--max-total-renamed` renames have been applied, to stop all processing
+#[derive(Debug)]
+pub struct RenameLimitReached(pub usize);
+
+impl std::fmt::Display for RenameLimitReached {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Stopping as the --max-total-renamed limit ({}) was reached.", self.0)
+    }
+}
+
+impl Error for RenameLimitReached {}
+
+/// Fail with the first of `paths` that exists, for `--fail-if-exists`
+pub fn ensure_absent(paths: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    for path in paths {
+        // `symlink_metadata` so that a dangling symlink still counts as being in the way
+        match std::fs::symlink_metadata(path) {
+            Ok(_) => return Err(format!("{} exists (--fail-if-exists)", path.display()).into()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => return Err(format!("Couldn't check {}: {}", path.display(), e).into()),
+        }
+    }
+    Ok(())
+}
+
+/// Count the files and directories whose names are longer than `max_len`
+///
+/// This is a single O(1)-memory pass with no extension splitting or grouping, so it's only an
+/// estimate: it also counts names that would end up being skipped.
+pub fn estimate_renames(paths: &[PathBuf], max_len: usize) -> Result<usize, Box<dyn Error>> {
+    let mut count = 0;
+    for path in paths {
+        for entry in WalkDir::new(path) {
+            let entry = entry?;
+            if entry.path().file_name().is_some_and(|name| name.as_bytes().len() > max_len) {
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+pub fn process_files(args: &CliArgs, state: &mut RunState) -> Result<(), Box<dyn Error>> {
+    // Ordered so output is grouped by parent directory rather than in hash order
+    let mut file_groups = BTreeMap::new();
+
+    let policy = args.secondary_ext_policy()?;
+    let known_exts = args.known_primary_exts()?;
 
     // First pass: Collect files by RStem and parent directory
     for path in &args.path {
-        for entry in WalkDir::new(path).contents_first(true) {
-            let path =
-                entry.as_ref().map(|e| e.path().to_path_buf()).unwrap_or_else(|_| PathBuf::new());
-            if path.is_dir() {
+        for entry in WalkDir::new(path).contents_first(true).sort_by_file_name() {
+            // The type was already fetched during traversal, so this avoids another `stat` call
+            if entry.as_ref().is_ok_and(|e| e.file_type().is_dir()) {
                 continue;
             }
+            let path =
+                entry.as_ref().map(|e| e.path().to_path_buf()).unwrap_or_else(|_| PathBuf::new());
 
             let parent = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
             let fname = path.file_name().map(|n| n.to_os_string()).unwrap_or_else(OsString::new);
 
-            let (r_stem, secondary_ext, primary_ext) =
-                split_rstem_ext(&fname, args.secondary_ext_len);
+            let parts = split_rstem_ext(&fname, &policy, args.secondary_ext_levels());
+            // Filtering goes by the real extension, even if it's about to be cut with the stem
+            if known_exts.as_ref().is_some_and(|known| !parts.has_primary_ext_in(known)) {
+                explain_unknown_ext(args, &path);
+                continue;
+            }
+            state.count_seen(extension_key(&parts));
+            let parts = if args.no_keep_primary_ext {
+                FileParts { rstem: fname.clone(), secondary_exts: Vec::new(), primary_ext: None }
+            } else {
+                parts
+            };
+            let budget = args.ext_budget();
+            let parts = if args.stem_only {
+                Some(parts).filter(|parts| parts.ext_bytes() <= budget)
+            } else {
+                fit_extension_budget(parts, budget)
+            };
+            let Some(parts) = parts else {
+                if fname.len() > args.max_len.get() {
+                    eprintln!(
+                        "Warning: Skipping '{}' as its extension exceeds the extension byte \
+                         budget ({}).",
+                        path.display(),
+                        budget
+                    );
+                }
+                continue;
+            };
 
-            file_groups.entry((parent, r_stem)).or_insert_with(Vec::new).push((
-                path,
-                secondary_ext,
-                primary_ext,
-            ));
+            file_groups
+                .entry((parent, parts.rstem.clone()))
+                .or_insert_with(Vec::new)
+                .push((path, parts));
+        }
+    }
+
+    if args.verbose_groups || args.verbose >= 2 {
+        for ((parent_dir, r_stem), files) in &file_groups {
+            let max_stem_bytes = args.stem_budget(files);
+            eprintln!("{}", describe_group(parent_dir, r_stem, files, max_stem_bytes));
         }
     }
 
     // Second pass: Process RStem groups
+    let trailing_pattern = args.trailing_pattern();
     for ((parent_dir, r_stem), files) in file_groups {
         let files_slice = files.as_slice();
-        let max_stem_bytes = calculate_max_stem_bytes(files_slice, args.max_len);
-        let truncated = truncate_stem(r_stem, max_stem_bytes, args.word_boundaries);
+        let max_stem_bytes = args.stem_budget(files_slice);
+        if args.verbose >= 3 {
+            eprintln!(
+                "{}",
+                describe_stem_budget(&parent_dir, &r_stem, files_slice, max_stem_bytes)
+            );
+        }
+        let r_stem = match &trailing_pattern {
+            Some(pattern) if r_stem.len() > args.kept_stem_len(&r_stem, max_stem_bytes) => {
+                trim_trailing(r_stem, pattern)
+            },
+            _ => r_stem,
+        };
+        let truncated = args.truncate_strategy.apply(
+            r_stem,
+            max_stem_bytes,
+            args.max_stem_utf8_chars.map(NonZeroUsize::get),
+            args.word_boundaries,
+        );
 
-        for (path, se, pe) in files {
-            let new_name = build_new_name(truncated.clone(), se, pe);
-            if new_name.len() > args.max_len {
+        for (path, parts) in files {
+            if args.verbose_extensions || args.verbose >= 2 {
+                let fname = path.file_name().unwrap_or_default();
+                eprintln!("{}", describe_extensions(fname, &parts, max_stem_bytes));
+            }
+            let new_name = build_new_name(&truncated, &parts);
+            if new_name.len() > args.max_len.get() {
                 eprintln!(
                     "Warning: Skipping '{}' as truncated name length ({}) exceeds max_len ({}).",
                     path.display(),
@@ -295,34 +1117,43 @@ pub fn process_files(args: &CliArgs) -> Result<(), Box<dyn Error>> {
             }
 
             let new_path = parent_dir.join(&new_name);
-            if new_path != path {
-                println!("Renaming: {:?} → {:?}", path.file_name().unwrap(), new_name);
-                if !args.dry_run {
-                    std::fs::rename(&path, &new_path)?;
-                }
+            if new_path != path && rename_path(args, state, RenameKind::File, &path, &new_path)? {
+                // Tallied by the real extension, like in the first pass, whatever became of it
+                let fname = path.file_name().unwrap_or_default();
+                let key =
+                    extension_key(&split_rstem_ext(fname, &policy, args.secondary_ext_levels()));
+                state.count_renamed(key, &path, &new_path);
             }
         }
     }
-
     Ok(())
 }
 
-pub fn process_directories(args: &CliArgs) -> Result<(), Box<dyn Error>> {
+/// Say why an over-long file is being left alone by `--limit-to-known-extensions`, with `-v`
+fn explain_unknown_ext(args: &CliArgs, path: &Path) {
+    if args.verbose >= 1 && path.file_name().is_some_and(|name| name.len() > args.max_len.get()) {
+        eprintln!("Skipping '{}' as its extension isn't a known one.", path.display());
+    }
+}
+
+pub fn process_directories(args: &CliArgs, state: &mut RunState) -> Result<(), Box<dyn Error>> {
     for path in &args.path {
         for entry in WalkDir::new(path).contents_first(true) {
-            let path = entry?.into_path();
-            if path.is_dir() {
-                let new_path =
-                    trunc_path(&path, args.max_len, args.secondary_ext_len, args.word_boundaries)?;
-                if new_path != path {
-                    println!(
-                        "Truncating directory: {:?} → {:?}",
-                        path.file_name().unwrap(),
-                        new_path.file_name().unwrap()
-                    );
-                    if !args.dry_run {
-                        std::fs::rename(&path, &new_path)?;
-                    }
+            let entry = entry?;
+            if entry.file_type().is_dir() {
+                let path = entry.path();
+                state.count_seen(DIRECTORY_KEY.to_owned());
+                let new_path = trunc_path(
+                    path,
+                    true,
+                    args.max_len.get(),
+                    args.secondary_ext_len,
+                    args.word_boundaries,
+                )?;
+                if new_path != path
+                    && rename_path(args, state, RenameKind::Directory, path, &new_path)?
+                {
+                    state.count_renamed(DIRECTORY_KEY.to_owned(), path, &new_path);
                 }
             }
         }
@@ -330,55 +1161,659 @@ pub fn process_directories(args: &CliArgs) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-pub fn calculate_max_stem_bytes(
-    files: &[(PathBuf, Option<OsString>, Option<OsString>)],
-    max_len: usize,
-) -> usize {
+/// Shorten over-long extensions for `--extension-only`, without grouping or touching stems
+pub fn process_extensions(args: &CliArgs, state: &mut RunState) -> Result<(), Box<dyn Error>> {
+    let policy = args.secondary_ext_policy()?;
+    let known_exts = args.known_primary_exts()?;
+    for path in &args.path {
+        for entry in WalkDir::new(path).contents_first(true).sort_by_file_name() {
+            let entry = entry?;
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let path = entry.path();
+            let Some(fname) = path.file_name() else {
+                continue;
+            };
+
+            let parts = split_rstem_ext(fname, &policy, args.secondary_ext_levels());
+            if known_exts.as_ref().is_some_and(|known| !parts.has_primary_ext_in(known)) {
+                explain_unknown_ext(args, path);
+                continue;
+            }
+            let key = extension_key(&parts);
+            state.count_seen(key.clone());
+            let parts = truncate_extension(parts, args.max_len.get());
+            let new_name = build_new_name(&parts.rstem, &parts);
+            if new_name.len() > args.max_len.get() {
+                eprintln!(
+                    "Warning: '{}' still exceeds max_len ({}) with its stem left unchanged.",
+                    path.display(),
+                    args.max_len
+                );
+            }
+
+            let new_path = path.with_file_name(&new_name);
+            if new_path != path && rename_path(args, state, RenameKind::File, path, &new_path)? {
+                state.count_renamed(key, path, &new_path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read `OLD<TAB>NEW` lines for `--stdin-format pairs`, skipping blank ones
+///
+/// All of the input is checked before anything is renamed, so one bad line renames nothing.
+pub fn parse_pairs(mut reader: impl BufRead) -> Result<Vec<(PathBuf, PathBuf)>, Box<dyn Error>> {
+    let mut input = Vec::new();
+    reader.read_to_end(&mut input)?;
+
+    let mut pairs = Vec::new();
+    for (number, line) in input.split(|&b| b == b'\n').enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<_> = line.split(|&b| b == b'\t').collect();
+        match fields[..] {
+            [old, new] if !old.is_empty() && !new.is_empty() => pairs.push((
+                PathBuf::from(OsStr::from_bytes(old)),
+                PathBuf::from(OsStr::from_bytes(new)),
+            )),
+            _ => {
+                return Err(format!(
+                    "Line {} of stdin isn't a single OLD<TAB>NEW pair (paths can't contain tabs)",
+                    number + 1
+                )
+                .into())
+            },
+        }
+    }
+    Ok(pairs)
+}
+
+/// Apply renames from `--stdin-format`, skipping any whose destination already exists
+///
+/// Every pair is checked before anything is renamed: sources must exist, destinations must be
+/// unique and have a directory to go in, and under `--strict` mustn't exist yet.
+pub fn process_pairs(
+    args: &CliArgs,
+    state: &mut RunState,
+    pairs: &[(PathBuf, PathBuf)],
+) -> Result<(), Box<dyn Error>> {
+    let policy = args.secondary_ext_policy()?;
+    let mut checked = Vec::with_capacity(pairs.len());
+    let mut destinations = HashSet::new();
+    for (path, new_path) in pairs {
+        let metadata = std::fs::symlink_metadata(path)
+            .map_err(|e| format!("Couldn't read '{}': {}", path.display(), e))?;
+        let (kind, key) = if metadata.is_dir() {
+            (RenameKind::Directory, DIRECTORY_KEY.to_owned())
+        } else {
+            let fname = path.file_name().unwrap_or_default();
+            (
+                RenameKind::File,
+                extension_key(&split_rstem_ext(fname, &policy, args.secondary_ext_levels())),
+            )
+        };
+
+        let parent = match new_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        if !parent.is_dir() {
+            return Err(format!(
+                "Can't rename '{}' to '{}' as '{}' isn't a directory",
+                path.display(),
+                new_path.display(),
+                parent.display()
+            )
+            .into());
+        }
+        if !destinations.insert(new_path) {
+            return Err(
+                format!("More than one path would be renamed to '{}'", new_path.display()).into()
+            );
+        }
+
+        // Decided up front, so a dry run skips exactly what the real run would
+        let exists = std::fs::symlink_metadata(new_path).is_ok();
+        if exists && args.strict {
+            return Err(format!("'{}' already exists", new_path.display()).into());
+        }
+        checked.push((kind, key, exists));
+    }
+
+    for ((path, new_path), (kind, key, exists)) in pairs.iter().zip(checked) {
+        state.count_seen(key.clone());
+        if exists {
+            eprintln!(
+                "Warning: Skipping '{}' as '{}' already exists.",
+                path.display(),
+                new_path.display()
+            );
+            continue;
+        }
+        if rename_path(args, state, kind, path, new_path)? {
+            state.count_renamed(key, path, new_path);
+        }
+    }
+    Ok(())
+}
+
+/// Per-extension tallies for `--summarize-by-extension`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExtensionStats {
+    pub total: usize,
+    pub renamed: usize,
+    pub bytes_saved: usize,
+}
+
+/// What directories are tallied under in `--summarize-by-extension`
+pub const DIRECTORY_KEY: &str = "(directory)";
+
+/// What a file is tallied under in `--summarize-by-extension`: its lowercased primary extension
+pub fn extension_key(parts: &FileParts) -> String {
+    match &parts.primary_ext {
+        Some(ext) => ext.to_string_lossy().to_lowercase(),
+        None => "(none)".to_owned(),
+    }
+}
+
+/// Render the `--summarize-by-extension` table, most renamed extensions first
+pub fn format_extension_summary(stats: &BTreeMap<String, ExtensionStats>) -> String {
+    let mut rows: Vec<_> = stats.iter().collect();
+    // Stable, so ties stay in alphabetical order
+    rows.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.renamed));
+
+    let width = rows.iter().map(|(ext, _)| ext.chars().count()).max().unwrap_or(0).max(9);
+    let mut table =
+        format!("| {:<width$} | Total files | Renamed | Avg bytes saved |\n", "Extension");
+    table.push_str(&format!(
+        "|{}|-------------|---------|-----------------|\n",
+        "-".repeat(width + 2)
+    ));
+    for (ext, stats) in rows {
+        let avg = match stats.renamed {
+            0 => "-".to_owned(),
+            renamed => format!("{:.1}", stats.bytes_saved as f64 / renamed as f64),
+        };
+        table.push_str(&format!(
+            "| {:<width$} | {:>11} | {:>7} | {:>15} |\n",
+            ext, stats.total, stats.renamed, avg
+        ));
+    }
+    table
+}
+
+/// The longest file name most POSIX filesystems accept, in bytes
+pub const NAME_MAX: usize = 255;
+
+/// What sort of filesystem entry is being renamed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenameKind {
+    File,
+    Directory,
+}
+
+impl RenameKind {
+    fn label(self) -> &'static str {
+        match self {
+            RenameKind::File => "Renaming",
+            RenameKind::Directory => "Truncating directory",
+        }
+    }
+}
+
+/// Report a rename and, unless this is a dry run, perform it
+///
+/// Returns whether the rename went ahead, or would have in a non-interactive dry run.
+pub fn rename_path(
+    args: &CliArgs,
+    state: &mut RunState,
+    kind: RenameKind,
+    path: &Path,
+    new_path: &Path,
+) -> Result<bool, Box<dyn Error>> {
+    if args.rename_only_ascii_safe && !new_path.file_name().unwrap_or_default().is_ascii() {
+        eprintln!("Warning: Skipping '{}' as its truncated name isn't pure ASCII.", path.display());
+        return Ok(false);
+    }
+
+    if let Some(hook) = &args.before_rename_hook {
+        let hook_args = [path.as_os_str(), new_path.as_os_str()];
+        match run_hook(hook, &hook_args, args.hook_timeout()) {
+            Ok(status) if status.success() => {},
+            Ok(status) => {
+                eprintln!(
+                    "Warning: Skipping '{}' as --before-rename-hook rejected it ({}).",
+                    path.display(),
+                    status
+                );
+                return Ok(false);
+            },
+            Err(msg) => {
+                eprintln!(
+                    "Error: Skipping '{}' as --before-rename-hook failed: {}",
+                    path.display(),
+                    msg
+                );
+                return Ok(false);
+            },
+        }
+    }
+
+    // Only the output is affected; the rename itself still uses the real paths
+    let mut show = |path| match &args.path_relative_to {
+        Some(base) => display_path(path, base).unwrap_or_else(|absolute| {
+            if !state.warned_outside_base {
+                state.warned_outside_base = true;
+                eprintln!(
+                    "Warning: '{}' isn't under --path-relative-to '{}', so paths outside it are \
+                     shown in full.",
+                    absolute.display(),
+                    base.display()
+                );
+            }
+            absolute
+        }),
+        None => Cow::Borrowed(path),
+    };
+    let (shown_path, shown_new_path) = (show(path), show(new_path));
+    // Plain output shows bare names, unless there's a --path-relative-to to show paths against
+    // or the pairs from --stdin-format could be moving things between directories
+    let shown_name = |shown: &Path| match (&args.path_relative_to, args.stdin_format) {
+        (None, None) => display_name(shown.file_name().unwrap(), args.output_encoding),
+        _ => display_name(shown.as_os_str(), args.output_encoding),
+    };
+
+    match (&args.dry_run_sep, args.dry_run) {
+        _ if args.simulate_rename => println!(
+            "mv U+\"{}\" U+\"{}\"",
+            unicode_escape(shown_path.as_os_str()),
+            unicode_escape(shown_new_path.as_os_str())
+        ),
+        (Some(sep), true) => {
+            let record = format_pair(&shown_path, &shown_new_path, sep, args.output_encoding);
+            std::io::stdout().lock().write_all(&record)?
+        },
+        _ if args.dry_run_compact && args.dry_run => {
+            println!("{}", format_compact(path, new_path, args.output_encoding))
+        },
+        _ => println!(
+            "{}: {} → {}",
+            kind.label(),
+            shown_name(&shown_path),
+            shown_name(&shown_new_path)
+        ),
+    }
+
+    let apply = if args.interactive {
+        std::io::stdout().flush()?;
+        // Without --dry-run, renaming is the default answer. With it, only a "yes" renames.
+        confirm(!args.dry_run, &mut std::io::stdin().lock(), &mut std::io::stderr())?
+    } else {
+        !args.dry_run
+    };
+
+    let renamed = apply && !args.simulate_rename;
+    if renamed {
+        if let (Some(suffix), RenameKind::File) = (&args.backup_suffix, kind) {
+            if let Err(msg) = backup_file(path, suffix) {
+                eprintln!(
+                    "Warning: Skipping '{}' as it couldn't be backed up: {}",
+                    path.display(),
+                    msg
+                );
+                return Ok(false);
+            }
+        }
+        std::fs::rename(path, new_path)?;
+        state.renamed += 1;
+    }
+
+    if let Some(hook) = &args.after_rename_hook {
+        if renamed || args.dry_run || args.simulate_rename {
+            let mut hook_args = vec![path.as_os_str(), new_path.as_os_str()];
+            if !renamed {
+                hook_args.push(OsStr::new("dry_run"));
+            }
+            let result = run_hook(hook, &hook_args, args.hook_timeout()).and_then(|status| {
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(format!("`{}` {}", hook, status).into())
+                }
+            });
+            if let Err(msg) = result {
+                if args.strict {
+                    return Err(msg);
+                }
+                eprintln!("Warning: --after-rename-hook failed for '{}': {}", path.display(), msg);
+            }
+        }
+    }
+
+    match args.max_total_renamed {
+        Some(limit) if renamed && state.renamed >= limit.get() => {
+            Err(RenameLimitReached(limit.get()).into())
+        },
+        _ => Ok(renamed || !args.interactive),
+    }
+}
+
+/// Run `cmd` through `sh` with `hook_args` appended as arguments, killing it if it's still
+/// running after `timeout`
+pub fn run_hook(
+    cmd: &str,
+    hook_args: &[&OsStr],
+    timeout: Duration,
+) -> Result<ExitStatus, Box<dyn Error>> {
+    // `"$@"` is what makes the paths arguments to CMD rather than just to the shell
+    let mut child = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", cmd))
+        .arg("--")
+        .args(hook_args)
+        .spawn()?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Err(format!("`{}` timed out after {} ms", cmd, timeout.as_millis()).into());
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Copy a file to its own name plus `suffix`, refusing to overwrite anything or to exceed
+/// `NAME_MAX`
+pub fn backup_file(path: &Path, suffix: &OsStr) -> Result<PathBuf, Box<dyn Error>> {
+    let mut backup_name = path.file_name().unwrap_or_default().to_os_string();
+    backup_name.push(suffix);
+    if backup_name.len() > NAME_MAX {
+        return Err(format!("backup name would be longer than {} bytes", NAME_MAX).into());
+    }
+
+    let backup = path.with_file_name(backup_name);
+    if std::fs::symlink_metadata(&backup).is_ok() {
+        return Err(format!("'{}' already exists", backup.display()).into());
+    }
+    std::fs::copy(path, &backup)?;
+    Ok(backup)
+}
+
+/// Ask whether to apply the rename just reported, treating an empty answer as `default` and
+/// end of input as "no"
+pub fn confirm(
+    default: bool,
+    input: &mut impl BufRead,
+    prompt: &mut impl Write,
+) -> Result<bool, Box<dyn Error>> {
+    write!(prompt, "Apply this rename? {} ", if default { "[Y/n]" } else { "[y/N]" })?;
+    prompt.flush()?;
+
+    let mut answer = String::new();
+    if input.read_line(&mut answer)? == 0 {
+        return Ok(false);
+    }
+    Ok(match answer.trim().to_ascii_lowercase().as_str() {
+        "" => default,
+        "y" | "yes" => true,
+        _ => false,
+    })
+}
+
+/// `path` relative to `base` for `--path-relative-to`, comparing both as absolute paths
+///
+/// Paths outside `base` come back absolute, as the error.
+pub fn display_path<'a>(path: &'a Path, base: &Path) -> Result<Cow<'a, Path>, Cow<'a, Path>> {
+    let absolute = std::path::absolute(path).map_or(Cow::Borrowed(path), Cow::Owned);
+    let relative = std::path::absolute(base)
+        .ok()
+        .and_then(|base| absolute.strip_prefix(base).ok().map(Path::to_path_buf));
+    match relative {
+        Some(relative) if relative.as_os_str().is_empty() => Ok(Cow::Borrowed(Path::new("."))),
+        Some(relative) => Ok(Cow::Owned(relative)),
+        None => Err(absolute),
+    }
+}
+
+/// Build one `OLD<SEP>NEW` record for `--dry-run-sep`, which is raw bytes by default
+pub fn format_pair(
+    path: &Path,
+    new_path: &Path,
+    sep: &str,
+    encoding: Option<OutputEncoding>,
+) -> Vec<u8> {
+    let encode = |path: &Path| match encoding {
+        Some(encoding) => encode_name(path.as_os_str(), encoding).into_bytes(),
+        None => path.as_os_str().as_bytes().to_vec(),
+    };
+
+    let mut record = encode(path);
+    record.extend_from_slice(sep.as_bytes());
+    record.extend_from_slice(&encode(new_path));
+    record.push(if sep == "\0" { b'\0' } else { b'\n' });
+    record
+}
+
+/// Build one `OLD → NEW` line of bare file names for `--dry-run-compact`
+pub fn format_compact(path: &Path, new_path: &Path, encoding: Option<OutputEncoding>) -> String {
+    let name = |path: &Path| {
+        let name = path.file_name().unwrap_or_default();
+        match encoding {
+            Some(encoding) => encode_name(name, encoding),
+            None => name.to_string_lossy().into_owned(),
+        }
+    };
+    format!("{} → {}", name(path), name(new_path))
+}
+
+/// Render a name for human-readable output, as `{:?}` would unless another encoding was asked for
+pub fn display_name(name: &OsStr, encoding: Option<OutputEncoding>) -> String {
+    match encoding {
+        Some(encoding) => encode_name(name, encoding),
+        None => format!("{:?}", name),
+    }
+}
+
+/// Escape a name for `--simulate-rename`, keeping only printable ASCII as-is
+pub fn unicode_escape(name: &OsStr) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for chunk in name.as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' | '\\' => {
+                    escaped.push('\\');
+                    escaped.push(c);
+                },
+                ' '..='~' => escaped.push(c),
+                '\0'..='\u{FFFF}' => escaped.push_str(&format!("\\u{:04X}", c as u32)),
+                _ => escaped.push_str(&format!("\\U{:08X}", c as u32)),
+            }
+        }
+        for b in chunk.invalid() {
+            escaped.push_str(&format!("\\x{:02X}", b));
+        }
+    }
+    escaped
+}
+
+/// Render a name's bytes using the given `--output-encoding`
+pub fn encode_name(name: &OsStr, encoding: OutputEncoding) -> String {
+    let bytes = name.as_bytes();
+    match encoding {
+        OutputEncoding::Utf8 => name.to_string_lossy().into_owned(),
+        OutputEncoding::Escaped => {
+            let mut escaped = String::with_capacity(bytes.len());
+            for &b in bytes {
+                match b {
+                    b'\\' => escaped.push_str("\\\\"),
+                    b' '..=b'~' => escaped.push(b as char),
+                    _ => escaped.push_str(&format!("\\x{:02X}", b)),
+                }
+            }
+            escaped
+        },
+        OutputEncoding::Hex => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+        OutputEncoding::Base64 => base64::engine::general_purpose::STANDARD.encode(bytes),
+    }
+}
+
+/// Fold secondary extensions back into the stem, innermost first, until the extensions fit in
+/// `budget` bytes
+///
+/// Returns `None` if the primary extension alone still doesn't fit.
+pub fn fit_extension_budget(mut parts: FileParts, budget: usize) -> Option<FileParts> {
+    while parts.ext_bytes() > budget {
+        if parts.secondary_exts.is_empty() {
+            return None;
+        }
+        let se = parts.secondary_exts.remove(0);
+        parts.rstem.push(".");
+        parts.rstem.push(se);
+    }
+    Some(parts)
+}
+
+pub fn calculate_max_stem_bytes(files: &[(PathBuf, FileParts)], max_len: usize) -> usize {
     let mut max_stem_bytes = usize::MAX;
-    for (_, se, pe) in files {
-        let ext_bytes = pe.as_ref().map(|e| e.as_bytes().len() + 1).unwrap_or(0)
-            + se.as_ref().map(|e| e.as_bytes().len() + 1).unwrap_or(0);
-        max_stem_bytes = max_stem_bytes.min(max_len.saturating_sub(ext_bytes));
+    for (_, parts) in files {
+        max_stem_bytes = max_stem_bytes.min(max_len.saturating_sub(parts.ext_bytes()));
     }
     max_stem_bytes
 }
 
-pub fn truncate_stem(r_stem: OsString, max_stem_bytes: usize, word_boundaries: bool) -> OsString {
-    let r_stem_bytes = r_stem.as_bytes();
-    let mut truncated_bytes = &r_stem_bytes[..r_stem_bytes.len().min(max_stem_bytes)];
+/// List the files sharing a stem, for `--verbose-groups`
+pub fn describe_group(
+    parent_dir: &Path,
+    r_stem: &OsStr,
+    files: &[(PathBuf, FileParts)],
+    max_stem_bytes: usize,
+) -> String {
+    let names: Vec<_> = files
+        .iter()
+        .map(|(path, _)| path.file_name().unwrap_or_default().to_string_lossy())
+        .collect();
+    format!(
+        "Group [{:?}, {:?}]: {} ({} file{}, max_stem_bytes={})",
+        parent_dir,
+        r_stem,
+        names.join(", "),
+        files.len(),
+        if files.len() == 1 { "" } else { "s" },
+        max_stem_bytes
+    )
+}
+
+/// Show how a file name was split up, for `--verbose-extensions`
+pub fn describe_extensions(fname: &OsStr, parts: &FileParts, max_stem_bytes: usize) -> String {
+    let mut description = format!("{} → stem={:?}", fname.to_string_lossy(), parts.rstem);
+    for ext in &parts.secondary_exts {
+        description.push_str(&format!(", sec_ext={:?} ({} bytes)", ext, ext.len()));
+    }
+    match &parts.primary_ext {
+        Some(ext) => description.push_str(&format!(", pri_ext={:?} ({} bytes)", ext, ext.len())),
+        None => description.push_str(", pri_ext=none"),
+    }
+    description.push_str(&format!(
+        ", total ext={} bytes, stem budget={} bytes",
+        parts.ext_bytes(),
+        max_stem_bytes
+    ));
+    description
+}
+
+/// Trace how `calculate_max_stem_bytes` arrived at a group's stem budget
+pub fn describe_stem_budget(
+    parent_dir: &Path,
+    r_stem: &OsStr,
+    files: &[(PathBuf, FileParts)],
+    max_stem_bytes: usize,
+) -> String {
+    let names: Vec<_> = files
+        .iter()
+        .map(|(path, _)| path.file_name().unwrap_or_default().to_string_lossy())
+        .collect();
+    let ext_bytes: Vec<_> = files.iter().map(|(_, parts)| parts.ext_bytes().to_string()).collect();
+    format!(
+        "Group ({:?}, {:?}): files=[{}], ext_bytes=[{}], max_stem_bytes={}",
+        parent_dir,
+        r_stem,
+        names.join(", "),
+        ext_bytes.join(", "),
+        max_stem_bytes
+    )
+}
 
-    while std::str::from_utf8(truncated_bytes).is_err() {
-        truncated_bytes = &truncated_bytes[..truncated_bytes.len().saturating_sub(1)];
+/// Strip the suffix matched by an anchored `pattern`, unless that would leave nothing (or only a
+/// hidden file's leading dot) behind
+pub fn trim_trailing(r_stem: OsString, pattern: &Regex) -> OsString {
+    // A hidden file's leading dot doesn't count as something left behind
+    let min_kept = usize::from(r_stem.as_bytes().starts_with(b".")) + 1;
+    match pattern.find(r_stem.as_bytes()) {
+        Some(found) if found.start() >= min_kept => {
+            OsStr::from_bytes(&r_stem.as_bytes()[..found.start()]).to_os_string()
+        },
+        _ => r_stem,
     }
+}
 
-    let mut truncated = OsStr::from_bytes(truncated_bytes).to_os_string();
+pub fn truncate_stem(r_stem: OsString, max_stem_bytes: usize, word_boundaries: bool) -> OsString {
+    let r_stem_bytes = r_stem.as_bytes();
+    let truncated_bytes = &r_stem_bytes[..r_stem_bytes.len().min(max_stem_bytes)];
+    let mut truncated_bytes = &truncated_bytes[..valid_utf8_prefix_len(truncated_bytes)];
 
+    // The bytes are valid UTF-8 by now, and a space byte can never be part of a multi-byte
+    // sequence, so searching the bytes directly finds the same space as searching the text would
     if word_boundaries {
-        let truncated_str = truncated.to_string_lossy();
-        if let Some(last_space) = truncated_str.rfind(' ') {
-            let space_bytes = truncated_str[..last_space].len();
-            if space_bytes > max_stem_bytes.saturating_sub(10) {
-                truncated = OsString::from(&truncated_str[..last_space]);
+        if let Some(last_space) = memchr::memrchr(b' ', truncated_bytes) {
+            if last_space > max_stem_bytes.saturating_sub(10) {
+                truncated_bytes = &truncated_bytes[..last_space];
             }
         }
     }
 
-    truncated
+    OsStr::from_bytes(truncated_bytes).to_os_string()
 }
 
-pub fn build_new_name(
-    truncated: OsString,
-    secondary_ext: Option<OsString>,
-    primary_ext: Option<OsString>,
-) -> OsString {
-    let mut new_name = truncated;
-    if let Some(se) = secondary_ext {
-        new_name.push(".");
-        new_name.push(se);
+/// Cut the primary extension down until all the extensions fit in `max_len` bytes
+///
+/// At least one character of it is kept, so this can fall short when the secondary extensions
+/// are long enough on their own.
+pub fn truncate_extension(mut parts: FileParts, max_len: usize) -> FileParts {
+    let excess = parts.ext_bytes().saturating_sub(max_len);
+    if let Some(ext) = parts.primary_ext.as_mut().filter(|_| excess > 0) {
+        let bytes = ext.as_bytes();
+        let keep = match valid_utf8_prefix_len(&bytes[..bytes.len().saturating_sub(excess)]) {
+            0 => bytes
+                .utf8_chunks()
+                .next()
+                .and_then(|chunk| chunk.valid().chars().next())
+                .map_or(bytes.len().min(1), char::len_utf8),
+            keep => keep,
+        };
+        *ext = OsStr::from_bytes(&bytes[..keep]).to_os_string();
     }
-    if let Some(pe) = primary_ext {
+    parts
+}
+
+/// Put a truncated stem back together with the extensions from `parts`
+///
+/// This is the one place new file names are assembled, so any post-truncation adjustments to
+/// them belong here.
+pub fn build_new_name(truncated: &OsStr, parts: &FileParts) -> OsString {
+    let mut new_name = OsString::with_capacity(truncated.len() + parts.ext_bytes());
+    new_name.push(truncated);
+    for ext in parts.secondary_exts.iter().chain(&parts.primary_ext) {
         new_name.push(".");
-        new_name.push(pe);
+        new_name.push(ext);
     }
     new_name
 }
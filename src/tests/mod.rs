@@ -30,10 +30,30 @@ impl TestDir {
 }
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
     use super::*;
-    use crate::{process_directories, process_files, split_rstem_ext, trunc_path, CliArgs};
-    use std::ffi::OsStr;
+    use crate::{
+        backup_file, calculate_max_stem_bytes, char_prefix_len, color_choice, confirm,
+        describe_extensions, describe_group, describe_stem_budget, display_name, display_path,
+        encode_name, ensure_absent, estimate_renames, extension_key, format_compact,
+        format_extension_summary, format_pair, known_compound_extensions, load_extension_whitelist,
+        parse_pairs, parse_ratio, parse_separator, parse_trailing_pattern, process_directories,
+        process_extensions, process_files, process_pairs, run_hook, split_rstem_ext,
+        split_stem_ext, trim_trailing, trunc_path, truncate_extension, truncate_stem,
+        unicode_escape, valid_utf8_prefix_len, CliArgs, ExtensionStats, OutputEncoding,
+        RenameLimitReached, RunState, SecondaryExtPolicy, TruncateStrategy, DIRECTORY_KEY,
+        KNOWN_COMPOUND_EXTENSIONS, NAME_MAX,
+    };
+    use clap::Parser;
+    use std::borrow::Cow;
+    use std::collections::BTreeMap;
+    use std::ffi::{OsStr, OsString};
+    use std::num::NonZeroUsize;
+    use std::time::Duration;
+
+    /// The default secondary extension policy
+    const MAX_LEN_6: SecondaryExtPolicy = SecondaryExtPolicy::MaxLen(6);
 
     /// Helper function to create test args
     fn test_args(
@@ -44,10 +64,51 @@ mod tests {
     ) -> CliArgs {
         CliArgs {
             path: vec![path],
-            max_len,
+            max_len: NonZeroUsize::new(max_len).expect("max_len must be at least 1"),
+            max_len_per_component: None,
             dry_run: false,
+            simulate_rename: false,
             secondary_ext_len: sec_ext_len,
+            strict: false,
+            no_keep_secondary_ext: false,
+            keep_primary_ext: false,
+            no_keep_primary_ext: false,
+            extension_whitelist_file: None,
+            limit_to_known_extensions: false,
+            no_builtin_known_exts: false,
+            max_secondary_exts: 1,
+            extension_max_components: None,
+            max_stem_utf8_chars: None,
+            truncate_strategy: TruncateStrategy::Right,
             word_boundaries,
+            extension_only: false,
+            stem_only: false,
+            interactive: false,
+            rename_only_ascii_safe: false,
+            max_total_renamed: None,
+            fail_if_exists: Vec::new(),
+            backup_suffix: None,
+            after_rename_hook: None,
+            before_rename_hook: None,
+            hook_timeout_ms: 5000,
+            extension_byte_budget: None,
+            min_extension_stem_ratio: 0.0,
+            dry_run_sep: None,
+            path_relative_to: None,
+            dry_run_compact: false,
+            trim_trailing_pattern: None,
+            trim_trailing_numbers: false,
+            verbose: 0,
+            verbose_groups: false,
+            verbose_extensions: false,
+            output_encoding: None,
+            color: clap::ColorChoice::Auto,
+            no_color: false,
+            stdin_format: None,
+            summarize_by_extension: false,
+            count_only: false,
+            list_known_exts: false,
+            truncate_strategy_help: false,
         }
     }
 
@@ -63,7 +124,7 @@ mod tests {
 
         for (input, max_len, expected) in test_cases {
             let path = test_dir.create_file(input, "content");
-            let result = trunc_path(&path, max_len, 6, false).expect("Truncation failed");
+            let result = trunc_path(&path, false, max_len, 6, false).expect("Truncation failed");
             let result_str = result.to_str().unwrap();
             let result_ext = result_str.rsplit('.').next().unwrap();
             let expected_ext = expected.rsplit('.').next().unwrap();
@@ -84,10 +145,12 @@ mod tests {
 
         for (input, sec_len, exp_stem, exp_sec, exp_pri) in test_cases {
             let input_os = OsStr::new(input);
-            let (stem, sec_ext, pri_ext) = split_rstem_ext(input_os, sec_len);
-            let stem_str = stem.to_string_lossy().into_owned();
-            let sec_ext_str = sec_ext.as_ref().map(|e| e.to_string_lossy().into_owned());
-            let pri_ext_str = pri_ext.as_ref().map(|e| e.to_string_lossy().into_owned());
+            let parts = split_rstem_ext(input_os, &SecondaryExtPolicy::MaxLen(sec_len), 1);
+            assert!(parts.secondary_exts.len() <= 1);
+            let stem_str = parts.rstem.to_string_lossy().into_owned();
+            let sec_ext_str =
+                parts.secondary_exts.first().map(|e| e.to_string_lossy().into_owned());
+            let pri_ext_str = parts.primary_ext.as_ref().map(|e| e.to_string_lossy().into_owned());
 
             assert_eq!(stem_str, exp_stem);
             assert_eq!(sec_ext_str.as_deref(), exp_sec);
@@ -107,12 +170,13 @@ mod tests {
 
         let args = test_args(test_dir.path().to_path_buf(), 12, 6, false);
 
-        process_files(&args).expect("File processing failed");
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
 
         let files: Vec<_> =
             fs::read_dir(test_dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
 
-        let rstems: Vec<_> = files.iter().map(|f| split_rstem_ext(f.as_ref(), 6).0).collect();
+        let rstems: Vec<_> =
+            files.iter().map(|f| split_rstem_ext(f.as_ref(), &MAX_LEN_6, 1).rstem).collect();
 
         let first_len = rstems[0].len();
         for rstem in rstems.iter().skip(1) {
@@ -135,7 +199,8 @@ mod tests {
 
         for (input, max_len, expected, word_boundaries) in test_cases {
             let path = test_dir.create_file(input, "content");
-            let result = trunc_path(&path, max_len, 6, word_boundaries).expect("Truncation failed");
+            let result =
+                trunc_path(&path, false, max_len, 6, word_boundaries).expect("Truncation failed");
             let result_str = result.file_name().unwrap().to_str().unwrap().to_string();
             assert_eq!(
                 result_str, expected,
@@ -157,7 +222,7 @@ mod tests {
 
         for (input, max_len, should_be_valid) in test_cases {
             let path = test_dir.create_file(input, "content");
-            let result = trunc_path(&path, max_len, 6, false).expect("Truncation failed");
+            let result = trunc_path(&path, false, max_len, 6, false).expect("Truncation failed");
 
             assert_eq!(result.to_str().is_some(), should_be_valid, "Result must be valid UTF-8");
         }
@@ -177,7 +242,8 @@ mod tests {
             let dir_path = test_dir.create_dir(dirname);
             let args = test_args(dir_path.clone(), max_len, 6, false);
 
-            process_directories(&args).expect("Directory processing failed");
+            process_directories(&args, &mut RunState::default())
+                .expect("Directory processing failed");
             let new_path = dir_path.parent().unwrap().join(expected);
             assert!(new_path.exists(), "Directory should be truncated to '{}'", expected);
         }
@@ -193,7 +259,7 @@ mod tests {
 
         let args = test_args(path.clone(), 6, 6, false); // max_len less than extensions
 
-        process_files(&args).expect("Processing should succeed");
+        process_files(&args, &mut RunState::default()).expect("Processing should succeed");
 
         // Verify file was skipped (not modified)
         assert!(path.exists(), "Original file should remain unchanged");
@@ -209,7 +275,7 @@ mod tests {
 
         let args2 = test_args(path2.clone(), 8, 6, false); // max_len less than minimum possible
 
-        process_files(&args2).expect("Processing should succeed");
+        process_files(&args2, &mut RunState::default()).expect("Processing should succeed");
 
         assert_eq!(
             path2.file_name().unwrap().to_str().unwrap(),
@@ -217,4 +283,1107 @@ mod tests {
             "File should be skipped when minimum possible length exceeds max_len"
         );
     }
+
+    #[test]
+    fn test_known_compound_extensions() {
+        // Rule: The lazily-built set mirrors the static list and stores entries without a dot
+        let known = known_compound_extensions();
+        assert_eq!(known.len(), KNOWN_COMPOUND_EXTENSIONS.len(), "List must not contain dupes");
+        assert!(known.contains("tar.gz"));
+        assert!(known.iter().all(|ext| !ext.starts_with('.')));
+
+        // --list-known-exts doesn't need any paths
+        let args = CliArgs::try_parse_from(["trunc_filenames", "--list-known-exts"])
+            .expect("--list-known-exts should parse without a path");
+        assert!(args.list_known_exts);
+        assert!(CliArgs::try_parse_from(["trunc_filenames"]).is_err(), "Paths still required");
+    }
+
+    #[test]
+    fn test_trim_trailing_pattern() {
+        // Rule: Only a suffix is stripped, and never the whole stem
+        let pattern = parse_trailing_pattern(crate::TRAILING_NUMBERS_PATTERN).unwrap();
+        let test_cases = vec![
+            ("document_20240115_123456", "document_20240115"),
+            ("document-42", "document"),
+            ("document42", "document"),
+            ("2024_document", "2024_document"),
+            ("123456", "123456"),
+            (".123456789012345", ".123456789012345"),
+            (".config_42", ".config"),
+        ];
+        for (input, expected) in test_cases {
+            let result = trim_trailing(input.into(), &pattern);
+            assert_eq!(result, OsStr::new(expected), "Trimming '{}' failed", input);
+        }
+
+        // Rule: Trimming only happens to stems which need truncating anyway
+        let test_dir = TestDir::new();
+        test_dir.create_file("document_20240115_123456.txt", "content");
+        test_dir.create_file("short_123.txt", "content");
+
+        let mut args = test_args(test_dir.path().to_path_buf(), 24, 6, false);
+        args.trim_trailing_numbers = true;
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+
+        assert!(test_dir.path().join("document_20240115.txt").exists());
+        assert!(test_dir.path().join("short_123.txt").exists());
+
+        // Rule: A hidden name made only of digits is truncated, not trimmed down to "."
+        let hidden_dir = test_dir.create_dir("hidden");
+        fs::write(hidden_dir.join(".123456789012345"), "content").unwrap();
+        let mut args = test_args(hidden_dir.clone(), 5, 0, false);
+        args.trim_trailing_numbers = true;
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+        assert!(hidden_dir.join(".1234").exists());
+    }
+
+    #[test]
+    fn test_dry_run_separator() {
+        // Rule: Shell-style escapes are unescaped, anything else is passed through as-is
+        assert_eq!(parse_separator(r"\t").unwrap(), "\t");
+        assert_eq!(parse_separator(r"\0").unwrap(), "\0");
+        assert_eq!(parse_separator(r" \\ ").unwrap(), " \\ ");
+        assert_eq!(parse_separator(" → ").unwrap(), " → ");
+        assert!(parse_separator(r"\x").is_err());
+        assert!(parse_separator("\\").is_err());
+
+        // Rule: Records are newline-terminated, unless NUL-separated for xargs -0
+        let (old, new) = (Path::new("dir/long name.txt"), Path::new("dir/long.txt"));
+        assert_eq!(format_pair(old, new, "\t", None), b"dir/long name.txt\tdir/long.txt\n");
+        assert_eq!(format_pair(old, new, "\0", None), b"dir/long name.txt\0dir/long.txt\0");
+    }
+
+    #[test]
+    fn test_dry_run_compact() {
+        // Rule: Compact lines show only the bare file names, whatever the parent directories
+        let (old, new) =
+            (Path::new("/a/long/parent/long name.txt"), Path::new("/a/long/parent/long.txt"));
+        assert_eq!(format_compact(old, new, None), "long name.txt → long.txt");
+        assert_eq!(
+            format_compact(old, new, Some(OutputEncoding::Hex)),
+            "6c6f6e67206e616d652e747874 → 6c6f6e672e747874"
+        );
+        assert!(CliArgs::try_parse_from(["trunc_filenames", "--dry-run-compact", "."]).is_err());
+    }
+
+    #[test]
+    fn test_path_relative_to() {
+        // Rule: Paths under DIR are shown relative to it, and anything else as an absolute path
+        let base = Path::new("/home/user/data");
+        let test_cases = vec![
+            ("/home/user/data/document/long_name.txt", Ok("document/long_name.txt")),
+            ("/home/user/data", Ok(".")),
+            ("/elsewhere/long_name.txt", Err("/elsewhere/long_name.txt")),
+        ];
+        for (path, expected) in test_cases {
+            let expected = expected.map(Path::new).map_err(Path::new);
+            let result = display_path(Path::new(path), base);
+            assert_eq!(result.as_deref().map_err(|e| &**e), expected, "Wrong result for {}", path);
+        }
+
+        // Relative paths are resolved against the working directory first
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(display_path(Path::new("sub/file"), &cwd).unwrap(), Path::new("sub/file"));
+        let absolute = cwd.join("sub/file");
+        assert_eq!(display_path(&absolute, Path::new(".")).unwrap(), Path::new("sub/file"));
+
+        // Paths outside DIR are only warned about once per run
+        let test_dir = TestDir::new();
+        let path = test_dir.create_file("a_long_file_name.txt", "content");
+        let mut args = test_args(path.clone(), 10, 6, false);
+        args.dry_run = true;
+        args.path_relative_to = Some(test_dir.path().join("elsewhere"));
+        let mut state = RunState::default();
+        process_files(&args, &mut state).expect("File processing failed");
+        assert!(state.warned_outside_base);
+    }
+
+    #[test]
+    fn test_directory_depth_order() {
+        // Rule: Children are renamed before their parents, or the child's old path would vanish
+        let test_dir = TestDir::new();
+        let deepest = test_dir.path().join("aaaaaaaa/bbbbbbbb/cccccccc");
+        fs::create_dir_all(&deepest).expect("Failed to create test directories");
+
+        let args = test_args(test_dir.path().join("aaaaaaaa"), 4, 6, false);
+        process_directories(&args, &mut RunState::default()).expect("Directory processing failed");
+
+        assert!(test_dir.path().join("aaaa/bbbb/cccc").is_dir(), "All levels should be renamed");
+        assert!(!test_dir.path().join("aaaaaaaa").exists());
+    }
+
+    #[test]
+    fn test_max_len_per_component() {
+        // Rule: Every component, directory or file name, fits the tighter of the two limits
+        for (max_len, per_component) in [(20, 9), (9, 20)] {
+            let test_dir = TestDir::new();
+            let dir = test_dir.create_dir("very_long_directory_name");
+            fs::write(dir.join("very_long_filename.txt"), "content").unwrap();
+
+            let mut args = test_args(dir, max_len, 6, false);
+            args.max_len_per_component = NonZeroUsize::new(per_component);
+            args.apply_max_len_per_component();
+            process_files(&args, &mut RunState::default()).expect("File processing failed");
+            process_directories(&args, &mut RunState::default())
+                .expect("Directory processing failed");
+            assert!(
+                test_dir.path().join("very_long/very_.txt").exists(),
+                "Limits {:?}",
+                (max_len, per_component)
+            );
+        }
+    }
+
+    #[test]
+    fn test_extension_byte_budget() {
+        // Rule: Over-budget secondary extensions are folded into the stem before skipping
+        let test_dir = TestDir::new();
+        test_dir.create_file("a_very_long_archive_name.tar.gz", "content");
+        let skipped = test_dir.create_file("some_long_name.markdown", "content");
+
+        let mut args = test_args(test_dir.path().to_path_buf(), 20, 6, false);
+        args.extension_byte_budget = Some(4);
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+
+        assert!(test_dir.path().join("a_very_long_archi.gz").exists());
+        assert!(skipped.exists(), "Files whose primary extension exceeds the budget are skipped");
+    }
+
+    #[test]
+    fn test_no_keep_primary_ext() {
+        // Rule: With --no-keep-primary-ext, the whole name is cut to max_len, extensions and all
+        let test_dir = TestDir::new();
+        test_dir.create_file("a_very_long_file_name.tar.gz", "content");
+        let mut args = test_args(test_dir.path().to_path_buf(), 14, 6, false);
+        args.no_keep_primary_ext = true;
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+        assert!(test_dir.path().join("a_very_long_fi").exists());
+
+        // The known-extension filter still sees the extension that's being cut
+        let test_dir = TestDir::new();
+        let unknown = test_dir.create_file("another_long_file_name.xyz", "content");
+        test_dir.create_file("another_long_file_name.gz", "content");
+        args.path = vec![test_dir.path().to_path_buf()];
+        args.limit_to_known_extensions = true;
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+        assert!(test_dir.path().join("another_long_f").exists());
+        assert!(unknown.exists(), "Unknown extensions are still left alone");
+
+        let parse = |flags: &[&str]| {
+            let args = CliArgs::try_parse_from([&["trunc_filenames"], flags, &["."]].concat());
+            args.map(|args| args.no_keep_primary_ext)
+        };
+        assert!(!parse(&[]).unwrap());
+        assert!(!parse(&["--no-keep-primary-ext", "--keep-primary-ext"]).unwrap(), "Last one wins");
+        assert!(parse(&["--no-keep-secondary-ext", "-s", "3"]).is_err());
+        assert!(parse(&["--no-keep-primary-ext", "--stem-only"]).is_err());
+        assert!(parse(&["--no-keep-primary-ext", "--extension-only"]).is_err());
+        assert!(!parse(&["--no-keep-primary-ext", "--keep-primary-ext", "--stem-only"]).unwrap());
+    }
+
+    #[test]
+    fn test_stem_only() {
+        // Rule: With --stem-only, extensions are never folded into the stem to meet the budget
+        let test_dir = TestDir::new();
+        let skipped = test_dir.create_file("a_very_long_archive_name.tar.gz", "content");
+        test_dir.create_file("another_long_file_name.gz", "content");
+
+        let mut args = test_args(test_dir.path().to_path_buf(), 20, 6, false);
+        args.extension_byte_budget = Some(4);
+        args.stem_only = true;
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+
+        assert!(skipped.exists(), "Files whose extensions exceed the budget are skipped");
+        assert!(test_dir.path().join("another_long_file.gz").exists());
+    }
+
+    #[test]
+    fn test_min_extension_stem_ratio() {
+        // Rule: The stem keeps at least F * max_len bytes, so extensions get the remainder
+        let test_dir = TestDir::new();
+        let mut args = test_args(test_dir.path().to_path_buf(), 20, 6, false);
+        assert_eq!(args.ext_budget(), usize::MAX, "A ratio of 0.0 imposes no budget");
+        args.min_extension_stem_ratio = 0.75;
+        assert_eq!(args.ext_budget(), 5);
+        args.extension_byte_budget = Some(3);
+        assert_eq!(args.ext_budget(), 3, "The tighter limit wins");
+        args.extension_byte_budget = None;
+
+        test_dir.create_file("long_archive_name_here.tar.bz2", "content");
+        let skipped = test_dir.create_file("name.extension1", "content");
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+
+        assert!(test_dir.path().join("long_archive_nam.bz2").exists());
+        assert!(skipped.exists());
+
+        assert!(parse_ratio("0.5").is_ok());
+        assert!(parse_ratio("1.5").is_err());
+        assert!(parse_ratio("half").is_err());
+    }
+
+    #[test]
+    fn test_color_choice() {
+        // Rule: The last of --color/--no-color wins, and nothing after `--` counts
+        use clap::ColorChoice;
+        let choice = |args: &[&str]| color_choice(args.iter().map(OsString::from));
+
+        assert_eq!(choice(&["trunc_filenames", "--no-color", "dir"]), ColorChoice::Never);
+        assert_eq!(choice(&["trunc_filenames", "--color=always"]), ColorChoice::Always);
+        assert_eq!(choice(&["trunc_filenames", "--color", "never"]), ColorChoice::Never);
+        assert_eq!(
+            choice(&["trunc_filenames", "--no-color", "--color=always"]),
+            ColorChoice::Always
+        );
+        assert_eq!(choice(&["trunc_filenames", "--", "--color=always"]), choice(&[]));
+    }
+
+    #[test]
+    fn test_hidden_files_stay_hidden() {
+        // Rule: A leading dot belongs to the stem and is never removed by truncation
+        assert_eq!(split_stem_ext(OsStr::new(".bashrc")), (OsStr::new(".bashrc"), None));
+        let parts = split_rstem_ext(OsStr::new(".config.tar.gz"), &MAX_LEN_6, 1);
+        assert_eq!(parts.rstem, ".config");
+        assert_eq!(parts.secondary_exts, vec![OsString::from("tar")]);
+        assert_eq!(parts.primary_ext.as_deref(), Some(OsStr::new("gz")));
+        assert_eq!(split_rstem_ext(OsStr::new(".tar.gz"), &MAX_LEN_6, 1).rstem, ".tar");
+
+        let test_dir = TestDir::new();
+        for (max_len, expected) in [(10, ".bashrc_ex"), (3, ".ba")] {
+            let path = test_dir.create_file(".bashrc_extended_config", "content");
+            let result = trunc_path(&path, false, max_len, 6, false).expect("Truncation failed");
+            assert_eq!(result.file_name().unwrap(), expected);
+
+            let args = test_args(path.clone(), max_len, 6, false);
+            process_files(&args, &mut RunState::default()).expect("File processing failed");
+            assert!(test_dir.path().join(expected).exists(), "Should be renamed to '{}'", expected);
+        }
+
+        for (strategy, expected) in
+            [(TruncateStrategy::Left, ".ig"), (TruncateStrategy::Middle, ".bg")]
+        {
+            let test_dir = TestDir::new();
+            test_dir.create_file(".bashrc_extended_config", "content");
+            let mut args = test_args(test_dir.path().to_path_buf(), 3, 6, false);
+            args.truncate_strategy = strategy;
+            process_files(&args, &mut RunState::default()).expect("File processing failed");
+            assert!(test_dir.path().join(expected).exists(), "{:?} should keep the dot", strategy);
+        }
+    }
+
+    #[test]
+    fn test_output_encoding() {
+        // Rule: Every encoding but utf8 represents arbitrary bytes unambiguously
+        use std::os::unix::ffi::OsStrExt;
+        let name = OsStr::from_bytes(b"caf\xC3\xA9\\\xFF.txt");
+        let test_cases = vec![
+            (OutputEncoding::Utf8, "café\\\u{FFFD}.txt"),
+            (OutputEncoding::Escaped, "caf\\xC3\\xA9\\\\\\xFF.txt"),
+            (OutputEncoding::Hex, "636166c3a95cff2e747874"),
+            (OutputEncoding::Base64, "Y2Fmw6lc/y50eHQ="),
+        ];
+        for (encoding, expected) in test_cases {
+            assert_eq!(encode_name(name, encoding), expected, "{:?} encoding failed", encoding);
+        }
+        assert_eq!(display_name(OsStr::new("a.txt"), None), "\"a.txt\"");
+
+        let (old, new) = (Path::new("ä"), Path::new("a"));
+        assert_eq!(format_pair(old, new, "\t", Some(OutputEncoding::Hex)), b"c3a4\t61\n");
+    }
+
+    #[test]
+    fn test_multiple_secondary_extensions() {
+        // Rule: Up to max_levels secondary extensions are peeled back, each within the length limit
+        let to_vec = |exts: &[&str]| exts.iter().map(OsString::from).collect::<Vec<_>>();
+        let test_cases = vec![
+            // (filename, max_levels, expected_stem, expected_sec_exts)
+            ("archive.tar.bz2.part", 1, "archive.tar", to_vec(&["bz2"])),
+            ("archive.tar.bz2.part", 2, "archive", to_vec(&["tar", "bz2"])),
+            ("archive.tar.bz2.part", 5, "archive", to_vec(&["tar", "bz2"])),
+            ("archive.longish.bz2.part", 5, "archive.longish", to_vec(&["bz2"])),
+            ("archive.tar.bz2.part", 0, "archive.tar.bz2", to_vec(&[])),
+        ];
+        for (input, max_levels, exp_stem, exp_sec) in test_cases {
+            let parts = split_rstem_ext(OsStr::new(input), &MAX_LEN_6, max_levels);
+            assert_eq!(parts.rstem, exp_stem, "Wrong stem for '{}' ({} levels)", input, max_levels);
+            assert_eq!(parts.secondary_exts, exp_sec);
+            assert_eq!(parts.primary_ext.as_deref(), Some(OsStr::new("part")));
+        }
+
+        let test_dir = TestDir::new();
+        test_dir.create_file("a_long_archive_name.tar.bz2.part", "content");
+        let mut args = test_args(test_dir.path().to_path_buf(), 20, 6, false);
+        args.max_secondary_exts = 2;
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+        assert!(test_dir.path().join("a_long_.tar.bz2.part").exists());
+
+        // Capping the components leaves the outer secondary extension in the stem
+        args.extension_max_components = Some(2);
+        assert_eq!(args.secondary_ext_levels(), 1);
+        test_dir.create_file("another_long_name.tar.bz2.part", "content");
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+        assert!(test_dir.path().join("another_lon.bz2.part").exists());
+        args.extension_max_components = Some(1);
+        assert_eq!(args.secondary_ext_levels(), 0);
+    }
+
+    #[test]
+    fn test_simulate_rename() {
+        // Rule: Only printable ASCII survives unescaped, and nothing is renamed
+        use std::os::unix::ffi::OsStrExt;
+        let test_cases: Vec<(&[u8], &str)> = vec![
+            (b"plain name.txt", "plain name.txt"),
+            ("日本.txt".as_bytes(), "\\u65E5\\u672C.txt"),
+            ("🌟.txt".as_bytes(), "\\U0001F31F.txt"),
+            (b"say \"hi\"\\\t", "say \\\"hi\\\"\\\\\\u0009"),
+            (b"bad\xFF", "bad\\xFF"),
+        ];
+        for (input, expected) in test_cases {
+            assert_eq!(unicode_escape(OsStr::from_bytes(input)), expected);
+        }
+
+        let test_dir = TestDir::new();
+        let path = test_dir.create_file("a_long_file_name.txt", "content");
+        let mut args = test_args(test_dir.path().to_path_buf(), 10, 6, false);
+        args.simulate_rename = true;
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+        assert!(path.exists(), "--simulate-rename must not rename anything");
+    }
+
+    #[test]
+    fn test_estimate_renames() {
+        // Rule: Every file or directory name over max_len counts, at any depth
+        let test_dir = TestDir::new();
+        test_dir.create_file("short.txt", "content");
+        test_dir.create_file("a_long_file_name.txt", "content");
+        let dir = test_dir.create_dir("a_long_directory");
+        fs::write(dir.join("another_long_name.txt"), "content").unwrap();
+
+        let count = estimate_renames(&[test_dir.path().to_path_buf()], 10).expect("Walk failed");
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn test_unchanged_paths_are_borrowed() {
+        // Rule: No-op truncations hand back the original path without allocating
+        let test_dir = TestDir::new();
+        let exact_fit = test_dir.create_file("abcdef.txt", "content");
+        let short_dir = test_dir.create_dir("short_dir");
+
+        for (path, max_len) in [(&exact_fit, 10), (&exact_fit, 50), (&short_dir, 10)] {
+            let result =
+                trunc_path(path, path.is_dir(), max_len, 6, false).expect("Truncation failed");
+            assert!(matches!(result, Cow::Borrowed(_)), "{:?} should be borrowed", path);
+        }
+    }
+
+    #[test]
+    fn test_max_len_validation() {
+        // Rule: max_len must leave room for a stem byte plus the longest secondary extension
+        let test_cases = vec![
+            // (max_len, sec_ext_len, valid)
+            (1, 0, true),
+            (8, 6, false),
+            (9, 6, true),
+            (4, 1, true),
+        ];
+        for (max_len, sec_ext_len, valid) in test_cases {
+            let args = test_args(PathBuf::from("."), max_len, sec_ext_len, false);
+            assert_eq!(args.validate().is_ok(), valid, "max_len={} -s {}", max_len, sec_ext_len);
+        }
+        assert!(CliArgs::try_parse_from(["trunc_filenames", "--max-len", "0", "."]).is_err());
+    }
+
+    #[test]
+    fn test_truncate_strategy() {
+        // Rule: Each strategy keeps its end(s) of the stem, on UTF-8 boundaries
+        let test_cases = vec![
+            (TruncateStrategy::Right, "a_very_long_file_name", 10, "a_very_lon"),
+            (TruncateStrategy::Left, "a_very_long_file_name", 10, "_file_name"),
+            (TruncateStrategy::Middle, "a_very_long_file_name", 10, "a_ver_name"),
+            (TruncateStrategy::Left, "日本語のファイル", 7, "イル"),
+            (TruncateStrategy::Middle, "日本語のファイル", 7, "日ル"),
+            (TruncateStrategy::Middle, "short", 10, "short"),
+            (TruncateStrategy::Left, ".bashrc_extended_config", 10, ".ed_config"),
+            (TruncateStrategy::Middle, ".bashrc_extended_config", 10, ".bashrnfig"),
+            (TruncateStrategy::Left, ".bashrc_extended_config", 1, "."),
+        ];
+        for (strategy, input, max_stem_bytes, expected) in test_cases {
+            let result = strategy.apply(input.into(), max_stem_bytes, None, false);
+            assert_eq!(result, expected, "Wrong result for {:?} on '{}'", strategy, input);
+        }
+
+        let test_dir = TestDir::new();
+        test_dir.create_file("a_very_long_file_name.txt", "content");
+        let mut args = test_args(test_dir.path().to_path_buf(), 14, 6, false);
+        args.truncate_strategy = TruncateStrategy::Left;
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+        assert!(test_dir.path().join("_file_name.txt").exists());
+    }
+
+    #[test]
+    fn test_max_stem_utf8_chars() {
+        // Rule: Stems are cut to whichever of the character and byte limits is tighter
+        let test_cases: Vec<(&[u8], usize, usize)> = vec![
+            (b"abcdef", 3, 3),
+            ("日本語です".as_bytes(), 2, 6),
+            (b"a\xFF\xFEb", 3, 3),
+            (b"short", 10, 5),
+        ];
+        for (stem, chars, expected) in test_cases {
+            assert_eq!(char_prefix_len(stem, chars), expected, "Wrong length for {:?}", stem);
+        }
+
+        let test_dir = TestDir::new();
+        test_dir.create_file("日本語のファイル名.txt", "content");
+        test_dir.create_file("plain_file_name.txt", "content");
+        let mut args = test_args(test_dir.path().to_path_buf(), 20, 6, false);
+        args.max_stem_utf8_chars = NonZeroUsize::new(4);
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+        assert!(test_dir.path().join("日本語の.txt").exists(), "The character limit is tighter");
+        assert!(test_dir.path().join("plai.txt").exists());
+
+        // The limit holds for whatever the strategy keeps, not just for the start of the stem
+        for (strategy, expected) in [
+            (TruncateStrategy::Right, "ééé.txt"),
+            (TruncateStrategy::Left, "aaa.txt"),
+            (TruncateStrategy::Middle, "ééa.txt"),
+        ] {
+            let test_dir = TestDir::new();
+            test_dir.create_file("éééaaaaaaaaaaaa.txt", "content");
+            let mut args = test_args(test_dir.path().to_path_buf(), 40, 6, false);
+            args.max_stem_utf8_chars = NonZeroUsize::new(3);
+            args.truncate_strategy = strategy;
+            process_files(&args, &mut RunState::default()).expect("File processing failed");
+            assert!(
+                test_dir.path().join(expected).exists(),
+                "{:?} should give {}",
+                strategy,
+                expected
+            );
+        }
+        let result = TruncateStrategy::Left.apply(".aaaaéé".into(), 40, Some(3), false);
+        assert_eq!(result, ".éé", "The leading dot counts as one of the characters");
+
+        assert!(CliArgs::try_parse_from(["trunc_filenames", "--max-stem-utf8-chars", "0", "."])
+            .is_err());
+    }
+
+    #[test]
+    fn test_clamp_secondary_ext_len() {
+        // Rule: A secondary_ext_len of at least max_len is cut to at most half of max_len, or
+        // rejected with --strict. Anything shorter is left for validate() to judge
+        let test_cases = vec![
+            // (max_len, sec_ext_len, strict, expected sec_ext_len or None for an error, warned)
+            (140, 6, false, Some(6), false),
+            (10, 10, false, Some(5), true),
+            (5, 10, false, Some(2), true),
+            (5, 10, true, None, false),
+            (10, 6, true, Some(6), false),
+            (8, 6, false, Some(6), false),
+            (5, 4, false, Some(4), false),
+            (6, 6, false, Some(3), true),
+            (4, 6, false, Some(1), true),
+            (3, 6, false, Some(0), true),
+            (1, 6, false, Some(0), true),
+            (1, 0, false, Some(0), false),
+        ];
+        for (max_len, sec_ext_len, strict, expected, warned) in test_cases {
+            let mut args = test_args(PathBuf::from("."), max_len, sec_ext_len, false);
+            args.strict = strict;
+            let result = args.clamp_secondary_ext_len();
+            let ctx = format!("max_len={} -s {} strict={}", max_len, sec_ext_len, strict);
+            match expected {
+                Some(len) => {
+                    assert_eq!(result.unwrap().is_some(), warned, "{}", ctx);
+                    assert_eq!(args.secondary_ext_len, len, "{}", ctx);
+                },
+                None => assert!(result.is_err(), "{}", ctx),
+            }
+        }
+
+        // Clamped values always pass validation, and the lengths in between still fail it
+        for max_len in 1..=20 {
+            for sec_ext_len in 0..=30 {
+                let mut args = test_args(PathBuf::from("."), max_len, sec_ext_len, false);
+                let warned = args.clamp_secondary_ext_len().unwrap().is_some();
+                let too_long = sec_ext_len > 0 && sec_ext_len + 3 > max_len;
+                let ctx = format!("max_len={} -s {}", max_len, sec_ext_len);
+                assert_eq!(args.validate().is_ok(), warned || !too_long, "{}", ctx);
+            }
+        }
+    }
+
+    #[test]
+    fn test_describe_group() {
+        // Rule: Each group lists its members, how many there are, and the shared stem budget
+        let files: Vec<_> = ["document.txt", "document.tar.gz", "document.pdf"]
+            .iter()
+            .map(|name| {
+                (PathBuf::from("dir").join(name), split_rstem_ext(OsStr::new(name), &MAX_LEN_6, 1))
+            })
+            .collect();
+        let max_stem_bytes = calculate_max_stem_bytes(&files, 140);
+        assert_eq!(
+            describe_group(Path::new("dir"), OsStr::new("document"), &files, max_stem_bytes),
+            r#"Group ["dir", "document"]: document.txt, document.tar.gz, document.pdf (3 files, max_stem_bytes=133)"#
+        );
+        assert!(describe_group(Path::new("dir"), OsStr::new("document"), &files[..1], 136)
+            .ends_with("(1 file, max_stem_bytes=136)"));
+    }
+
+    #[test]
+    fn test_describe_extensions() {
+        // Rule: Every preserved extension is listed with its size, along with the resulting budget
+        let describe = |name: &str, max_levels| {
+            let parts = split_rstem_ext(OsStr::new(name), &MAX_LEN_6, max_levels);
+            describe_extensions(OsStr::new(name), &parts, 140 - parts.ext_bytes())
+        };
+        assert_eq!(
+            describe("file.tar.gz", 1),
+            r#"file.tar.gz → stem="file", sec_ext="tar" (3 bytes), pri_ext="gz" (2 bytes), total ext=7 bytes, stem budget=133 bytes"#
+        );
+        assert_eq!(
+            describe("README", 1),
+            r#"README → stem="README", pri_ext=none, total ext=0 bytes, stem budget=140 bytes"#
+        );
+        assert!(describe("a.tar.bz2.part", 2).contains(r#"sec_ext="tar" (3 bytes), sec_ext="bz2""#));
+    }
+
+    #[test]
+    fn test_describe_stem_budget() {
+        // Rule: The trace shows each file's extension bytes and the resulting group budget
+        let files: Vec<_> = ["a.txt", "a.tar.gz"]
+            .iter()
+            .map(|name| {
+                (PathBuf::from("dir").join(name), split_rstem_ext(OsStr::new(name), &MAX_LEN_6, 1))
+            })
+            .collect();
+        // The budget is what the stem could grow to, not what this short stem happens to keep
+        let mut args = test_args(PathBuf::from("dir"), 140, 6, false);
+        args.max_stem_utf8_chars = NonZeroUsize::new(3);
+        let max_stem_bytes = args.stem_budget(&files);
+        assert_eq!(max_stem_bytes, calculate_max_stem_bytes(&files, 140));
+        assert_eq!(
+            describe_stem_budget(Path::new("dir"), OsStr::new("a"), &files, max_stem_bytes),
+            r#"Group ("dir", "a"): files=[a.txt, a.tar.gz], ext_bytes=[4, 7], max_stem_bytes=133"#
+        );
+        assert_eq!(args.kept_stem_len(OsStr::new("a"), max_stem_bytes), 1);
+        assert_eq!(args.kept_stem_len(OsStr::new("abcdef"), max_stem_bytes), 3);
+    }
+
+    #[test]
+    fn test_extension_whitelist_file() {
+        // Rule: With a whitelist, only listed (and built-in) secondary extensions are preserved
+        let test_dir = TestDir::new();
+        let whitelist = test_dir.create_file("whitelist.txt", "# Comment\n\nbackup\n  ORIG \n");
+
+        let exts = load_extension_whitelist(&whitelist, true).expect("Reading whitelist failed");
+        assert!(exts.contains(OsStr::new("backup")));
+        assert!(exts.contains(OsStr::new("orig")), "Entries are trimmed and lowercased");
+        assert!(exts.contains(OsStr::new("tar")), "Built-in compound extensions are included");
+        assert!(!exts.iter().any(|e| e.to_string_lossy().starts_with('#')));
+
+        let exts = load_extension_whitelist(&whitelist, false).expect("Reading whitelist failed");
+        assert!(!exts.contains(OsStr::new("tar")));
+
+        let policy = SecondaryExtPolicy::Whitelist(exts);
+        let test_cases = vec![
+            ("notes.backup.txt", "notes", Some("backup")),
+            ("notes.Orig.txt", "notes", Some("Orig")),
+            ("notes.sh.txt", "notes.sh", None),
+        ];
+        for (input, exp_stem, exp_sec) in test_cases {
+            let parts = split_rstem_ext(OsStr::new(input), &policy, 1);
+            assert_eq!(parts.rstem, exp_stem, "Wrong stem for '{}'", input);
+            assert_eq!(parts.secondary_exts.first().and_then(|e| e.to_str()), exp_sec);
+        }
+
+        assert!(load_extension_whitelist(&test_dir.path().join("missing"), true).is_err());
+    }
+
+    #[test]
+    fn test_limit_to_known_extensions() {
+        // Rule: Only files with a whitelisted or built-in primary extension are renamed
+        let test_dir = TestDir::new();
+        let whitelist = test_dir.create_file("whitelist.txt", "md\n");
+        let files_dir = test_dir.create_dir("files");
+        let unknown = files_dir.join("long_file_name.txt");
+        let no_ext = files_dir.join("long_file_name");
+        for name in
+            ["long_file_name.md", "long_archive.tar.GZ", "long_file_name.txt", "long_file_name"]
+        {
+            fs::write(files_dir.join(name), "").unwrap();
+        }
+
+        let mut args = test_args(files_dir.clone(), 10, 3, false);
+        args.extension_whitelist_file = Some(whitelist);
+        args.limit_to_known_extensions = true;
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+
+        assert!(files_dir.join("long_fi.md").exists());
+        assert!(files_dir.join("lon.tar.GZ").exists(), "Built-in extensions count as known");
+        assert!(unknown.exists(), "Unknown extensions are left alone");
+        assert!(no_ext.exists(), "Files without an extension are left alone");
+    }
+
+    #[test]
+    fn test_interactive_confirmation() {
+        // Rule: An empty answer takes the default, and running out of input never renames
+        use std::io::Cursor;
+        let test_cases = vec![
+            // (answer, default, expected)
+            ("y\n", false, true),
+            ("YES\n", false, true),
+            ("\n", false, false),
+            ("\n", true, true),
+            ("n\n", true, false),
+            ("maybe\n", true, false),
+            ("", true, false),
+        ];
+        for (answer, default, expected) in test_cases {
+            let mut prompt = Vec::new();
+            let result = confirm(default, &mut Cursor::new(answer), &mut prompt).unwrap();
+            assert_eq!(result, expected, "Answer {:?} with default {}", answer, default);
+        }
+
+        let mut prompt = Vec::new();
+        confirm(false, &mut Cursor::new(""), &mut prompt).unwrap();
+        assert_eq!(prompt, b"Apply this rename? [y/N] ");
+    }
+
+    #[test]
+    fn test_rename_only_ascii_safe() {
+        // Rule: A rename is skipped unless the new name is pure ASCII
+        let test_dir = TestDir::new();
+        let non_ascii = test_dir.create_file("naïve_long_name.txt", "content");
+        test_dir.create_file("plain_long_name.txt", "content");
+        let mut args = test_args(test_dir.path().to_path_buf(), 10, 6, false);
+        args.rename_only_ascii_safe = true;
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+
+        assert!(non_ascii.exists(), "Non-ASCII results leave the original alone");
+        assert!(test_dir.path().join("plain_.txt").exists());
+    }
+
+    #[test]
+    fn test_summarize_by_extension() {
+        // Rule: Extensions are tallied case-insensitively and listed by renames, most first
+        let mut stats = BTreeMap::new();
+        stats.insert("md".to_owned(), ExtensionStats { total: 1, renamed: 0, bytes_saved: 0 });
+        stats.insert("txt".to_owned(), ExtensionStats { total: 3, renamed: 2, bytes_saved: 16 });
+        assert_eq!(
+            format_extension_summary(&stats),
+            "| Extension | Total files | Renamed | Avg bytes saved |\n\
+             |-----------|-------------|---------|-----------------|\n\
+             | txt       |           3 |       2 |             8.0 |\n\
+             | md        |           1 |       0 |               - |\n"
+        );
+        let parts = split_rstem_ext(OsStr::new("long_readme"), &MAX_LEN_6, 1);
+        assert_eq!(extension_key(&parts), "(none)");
+
+        let test_dir = TestDir::new();
+        let files_dir = test_dir.create_dir("a_long_directory");
+        for name in ["long_FOO_name.TXT", "long_bar_name.txt", "short.txt"] {
+            fs::write(files_dir.join(name), "content").unwrap();
+        }
+        let mut args = test_args(files_dir.clone(), 10, 6, false);
+        args.dry_run = true;
+        let mut state = RunState::default();
+        process_files(&args, &mut state).expect("File processing failed");
+        process_directories(&args, &mut state).expect("Directory processing failed");
+        assert_eq!(
+            state.ext_stats["txt"],
+            ExtensionStats { total: 3, renamed: 2, bytes_saved: 14 }
+        );
+        let dirs = &state.ext_stats[DIRECTORY_KEY];
+        assert_eq!((dirs.total, dirs.renamed), (1, 1), "Directories are tallied too");
+
+        args.extension_only = true;
+        let mut state = RunState::default();
+        process_extensions(&args, &mut state).expect("Extension processing failed");
+        assert_eq!(state.ext_stats["txt"].total, 3, "--extension-only is tallied too");
+        args.extension_only = false;
+
+        args.no_keep_primary_ext = true;
+        let mut state = RunState::default();
+        process_files(&args, &mut state).expect("File processing failed");
+        let txt = &state.ext_stats["txt"];
+        assert_eq!((txt.total, txt.renamed), (3, 2), "Files are tallied by their real extension");
+
+        let pairs = [
+            (files_dir.join("long_bar_name.txt"), files_dir.join("bar.txt")),
+            (files_dir.clone(), test_dir.path().join("dir")),
+        ];
+        let mut state = RunState::default();
+        process_pairs(&args, &mut state, &pairs).expect("Dry run failed");
+        assert_eq!(
+            state.ext_stats["txt"],
+            ExtensionStats { total: 1, renamed: 1, bytes_saved: 10 }
+        );
+        assert_eq!(state.ext_stats[DIRECTORY_KEY].renamed, 1, "Pairs are tallied too");
+    }
+
+    #[test]
+    fn test_max_total_renamed() {
+        // Rule: Processing stops right after the Nth applied rename, files and directories alike
+        let test_dir = TestDir::new();
+        let files_dir = test_dir.create_dir("long_directory");
+        for name in ["first_long_name.txt", "second_long_name.txt"] {
+            fs::write(files_dir.join(name), "content").unwrap();
+        }
+
+        let mut args = test_args(files_dir.clone(), 10, 6, false);
+        args.max_total_renamed = NonZeroUsize::new(1);
+        args.dry_run = true;
+        let mut state = RunState::default();
+        process_files(&args, &mut state).expect("Dry runs don't count towards the limit");
+
+        args.dry_run = false;
+        let err = process_files(&args, &mut state).expect_err("The limit should be reached");
+        assert!(err.is::<RenameLimitReached>());
+        assert!(files_dir.join("first_.txt").exists());
+        assert!(files_dir.join("second_long_name.txt").exists(), "Nothing is renamed past N");
+
+        args.max_total_renamed = NonZeroUsize::new(3);
+        process_files(&args, &mut state).expect("File processing failed");
+        assert!(process_directories(&args, &mut state).is_err(), "Directory renames count too");
+        assert_eq!(state.renamed, 3);
+        assert!(
+            CliArgs::try_parse_from(["trunc_filenames", "--max-total-renamed", "0", "."]).is_err()
+        );
+        assert!(test_dir.path().join("long_direc").is_dir());
+    }
+
+    #[test]
+    fn test_fail_if_exists() {
+        // Rule: Any listed path existing, even as a dangling symlink, is an error
+        let test_dir = TestDir::new();
+        let missing = test_dir.path().join("missing");
+        assert!(ensure_absent(&[]).is_ok());
+        assert!(ensure_absent(std::slice::from_ref(&missing)).is_ok());
+
+        let present = test_dir.create_file("present", "content");
+        assert!(ensure_absent(&[missing.clone(), present]).is_err());
+
+        let dangling = test_dir.path().join("dangling");
+        std::os::unix::fs::symlink(&missing, &dangling).unwrap();
+        assert!(ensure_absent(&[dangling]).is_err());
+    }
+
+    #[test]
+    fn test_backup_suffix() {
+        // Rule: The original is kept under name + suffix, and a rename without a backup is skipped
+        let test_dir = TestDir::new();
+        let path = test_dir.create_file("very_long_name.txt", "content");
+        let mut args = test_args(path.clone(), 11, 6, false);
+        args.backup_suffix = Some(".bak".into());
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+
+        let backup = test_dir.path().join("very_long_name.txt.bak");
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "content");
+        assert!(test_dir.path().join("very_lo.txt").exists());
+        assert!(!path.exists());
+
+        // The backup from the first run is in the way now
+        let path = test_dir.create_file("very_long_name.txt", "content");
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+        assert!(path.exists(), "Files that can't be backed up aren't renamed");
+
+        let too_long = "x".repeat(NAME_MAX);
+        assert!(backup_file(&path, OsStr::new(&too_long)).is_err());
+    }
+
+    #[test]
+    fn test_extension_only() {
+        // Rule: Only the primary extension is shortened, and stems and directories are left alone
+        let test_dir = TestDir::new();
+        let dir = test_dir.create_dir("a_long_directory_name");
+        let long_ext = dir.join("notes.markdown_backup");
+        let long_stem = dir.join("a_very_long_stem_indeed.txt");
+        let short = dir.join("ok.tar.gz");
+        for path in [&long_ext, &long_stem, &short] {
+            fs::write(path, "").unwrap();
+        }
+        let mut args = test_args(dir.clone(), 10, 3, false);
+        args.extension_only = true;
+        process_extensions(&args, &mut RunState::default()).expect("Extension processing failed");
+
+        assert!(dir.join("notes.markdown_").exists());
+        assert!(long_stem.exists(), "Stems are never shortened");
+        assert!(short.exists());
+
+        let parts = split_rstem_ext(OsStr::new("a.tar.日本"), &MAX_LEN_6, 1);
+        let parts = truncate_extension(parts, 5);
+        assert_eq!(parts.primary_ext.as_deref(), Some(OsStr::new("日")), "Keeps one character");
+    }
+
+    #[test]
+    fn test_after_rename_hook() {
+        // Rule: The hook gets the old and new paths, plus "dry_run" when nothing was renamed
+        let test_dir = TestDir::new();
+        let log = test_dir.path().join("hook.log");
+        let files_dir = test_dir.create_dir("files");
+        let path = files_dir.join("very_long_name.txt");
+        fs::write(&path, "content").unwrap();
+
+        let mut args = test_args(files_dir.clone(), 11, 6, false);
+        args.after_rename_hook = Some(format!("printf '%s|%s|%s\\n' >> '{}'", log.display()));
+        args.dry_run = true;
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+        args.dry_run = false;
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+
+        let new_path = files_dir.join("very_lo.txt");
+        let expected = format!(
+            "{old}|{new}|dry_run\n{old}|{new}|\n",
+            old = path.display(),
+            new = new_path.display()
+        );
+        assert_eq!(fs::read_to_string(&log).unwrap(), expected);
+
+        args.after_rename_hook = Some("false".to_owned());
+        args.strict = true;
+        fs::write(&path, "content").unwrap();
+        assert!(
+            process_files(&args, &mut RunState::default()).is_err(),
+            "--strict stops at a failing hook"
+        );
+    }
+
+    #[test]
+    fn test_before_rename_hook() {
+        // Rule: A rename only happens if the hook succeeds within the timeout
+        let test_dir = TestDir::new();
+        let hook =
+            test_dir.create_file("hook.sh", r#"case "$1" in *accepted*) exit 0;; esac; exit 1"#);
+        let files_dir = test_dir.create_dir("files");
+        let rejected = files_dir.join("rejected_long_name.txt");
+        for path in [&rejected, &files_dir.join("accepted_long_name.txt")] {
+            fs::write(path, "content").unwrap();
+        }
+
+        let mut args = test_args(files_dir.clone(), 11, 6, false);
+        args.before_rename_hook = Some(format!("sh '{}'", hook.display()));
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+        assert!(files_dir.join("accepte.txt").exists());
+        assert!(rejected.exists(), "Renames the hook rejects are skipped");
+
+        let timeout = Duration::from_millis(50);
+        assert!(run_hook("exit 3", &[], timeout).is_ok_and(|status| status.code() == Some(3)));
+        assert!(run_hook("exec sleep 5", &[], timeout).is_err(), "Slow hooks time out");
+        args.hook_timeout_ms = 50;
+        args.before_rename_hook = Some("exec sleep 5".into());
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+        assert!(rejected.exists(), "Timed out hooks skip the rename");
+    }
+
+    #[test]
+    fn test_stdin_pairs() {
+        // Rule: Pairs are applied exactly, but never onto an existing path
+        use std::io::Cursor;
+
+        let test_dir = TestDir::new();
+        let old = test_dir.create_file("old_name.txt", "content");
+        let blocked = test_dir.create_file("blocked.txt", "content");
+        let taken = test_dir.create_file("taken.txt", "original");
+        let new = test_dir.path().join("new.txt");
+        let input = format!(
+            "{}\t{}\n\n{}\t{}\n",
+            old.display(),
+            new.display(),
+            blocked.display(),
+            taken.display()
+        );
+        let pairs = parse_pairs(Cursor::new(input)).expect("Parsing failed");
+        assert_eq!(pairs.len(), 2, "Blank lines are skipped");
+
+        let mut args = test_args(test_dir.path().to_path_buf(), 140, 6, false);
+        args.dry_run = true;
+        process_pairs(&args, &mut RunState::default(), &pairs).expect("Dry run failed");
+        assert!(old.exists(), "--dry-run is respected");
+
+        args.dry_run = false;
+        process_pairs(&args, &mut RunState::default(), &pairs).expect("Renaming failed");
+        assert!(new.exists());
+        assert!(blocked.exists());
+        assert_eq!(fs::read_to_string(&taken).unwrap(), "original");
+
+        args.strict = true;
+        assert!(
+            process_pairs(&args, &mut RunState::default(), &pairs[1..]).is_err(),
+            "--strict makes collisions errors"
+        );
+        args.strict = false;
+
+        // One bad pair stops anything from being renamed: a missing source or destination
+        // directory, a repeated destination, or with --strict an existing one
+        let source = test_dir.create_file("source.txt", "content");
+        let other = test_dir.create_file("other.txt", "content");
+        let missing = test_dir.path().join("missing.txt");
+        let no_dir = test_dir.path().join("no_such_dir").join("dest.txt");
+        let renamed = test_dir.path().join("renamed.txt");
+        for (bad_pair, strict, named) in [
+            ((missing, test_dir.path().join("dest.txt")), false, "missing.txt"),
+            ((other.clone(), no_dir), false, "no_such_dir"),
+            ((other.clone(), renamed.clone()), false, "renamed.txt"),
+            ((other.clone(), taken.clone()), true, "taken.txt"),
+        ] {
+            args.strict = strict;
+            let pairs = [(source.clone(), renamed.clone()), bad_pair];
+            let err = process_pairs(&args, &mut RunState::default(), &pairs)
+                .expect_err("Bad pairs should be rejected");
+            assert!(err.to_string().contains(named), "'{}' should name {}", err, named);
+            assert!(source.exists(), "Nothing is renamed before everything is checked");
+        }
+        args.strict = false;
+
+        assert!(
+            CliArgs::try_parse_from(["trunc_filenames", "--stdin-format", "pairs", "-i"]).is_err()
+        );
+
+        assert!(parse_pairs(Cursor::new("a\tb\tc\n")).is_err(), "Paths can't contain tabs");
+        assert!(parse_pairs(Cursor::new("just_one_path\n")).is_err());
+    }
+
+    /// Fill a directory with names of max_len + 1 to max_len + 100 bytes, process it with `args`,
+    /// and check that every resulting name fits
+    fn assert_max_len_invariant(test_dir: &TestDir, mut args: CliArgs, settings: &str) {
+        let extensions = ["txt", "tar.gz", "tar.bz2.part", "orig.backup.md"];
+        let filler = "word 日本語 🌟 αβγ_";
+        let max_len = args.max_len.get();
+        let files_dir = test_dir.create_dir("files");
+        args.path = vec![files_dir.clone()];
+
+        for len in max_len + 1..=max_len + 100 {
+            // A unique prefix and suffix keep truncated names from colliding, whichever end is cut
+            let mut stem = format!("{:03} ", len);
+            for c in filler.chars().cycle() {
+                if stem.len() + 5 >= len {
+                    break;
+                }
+                stem.push(c);
+            }
+            stem.push_str(&format!(" {:03}z", len));
+            for ext in extensions {
+                fs::write(files_dir.join(format!("{}.{}", stem, ext)), "").unwrap();
+            }
+            fs::create_dir(files_dir.join(&stem)).unwrap();
+        }
+
+        process_files(&args, &mut RunState::default()).expect("File processing failed");
+        process_directories(&args, &mut RunState::default()).expect("Directory processing failed");
+
+        let mut count = 0;
+        for entry in walkdir::WalkDir::new(test_dir.path()).min_depth(2) {
+            let name = entry.unwrap().file_name().to_owned();
+            assert!(
+                name.len() <= max_len,
+                "{:?} is longer than {} with {}",
+                name,
+                max_len,
+                settings
+            );
+            assert!(name.to_str().is_some(), "{:?} isn't valid UTF-8 with {}", name, settings);
+            count += 1;
+        }
+        assert_eq!(count, 100 * (extensions.len() + 1), "Names went missing with {}", settings);
+    }
+
+    #[test]
+    fn test_max_len_invariant() {
+        // Rule: Whatever the settings, no resulting name is ever longer than max_len
+        let mut combinations = Vec::new();
+        for word_boundaries in [false, true] {
+            for sec_ext_len in [0, 6] {
+                for max_secondary_exts in [1, 2] {
+                    for whitelist in [None, Some("backup\norig\n")] {
+                        for trim_trailing_numbers in [false, true] {
+                            for strategy in [
+                                TruncateStrategy::Right,
+                                TruncateStrategy::Left,
+                                TruncateStrategy::Middle,
+                            ] {
+                                combinations.push((
+                                    word_boundaries,
+                                    sec_ext_len,
+                                    max_secondary_exts,
+                                    whitelist,
+                                    trim_trailing_numbers,
+                                    strategy,
+                                ));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        for (word_boundaries, sec_ext_len, max_secondary_exts, whitelist, trim, strategy) in
+            combinations
+        {
+            let test_dir = TestDir::new();
+            let mut args = test_args(PathBuf::new(), 40, sec_ext_len, word_boundaries);
+            args.max_secondary_exts = max_secondary_exts;
+            args.trim_trailing_numbers = trim;
+            args.truncate_strategy = strategy;
+            args.extension_whitelist_file = whitelist.map(|w| test_dir.create_file("whitelist", w));
+
+            let settings = format!(
+                "-w={} -s {} --max-secondary-exts {} whitelist={:?} --trim-trailing-numbers={} \
+                 --truncate-strategy={:?}",
+                word_boundaries, sec_ext_len, max_secondary_exts, whitelist, trim, strategy
+            );
+            assert_max_len_invariant(&test_dir, args, &settings);
+        }
+    }
+
+    #[test]
+    fn test_no_rename_alias() {
+        // Rule: --no-rename is exactly --dry-run, not a separate flag
+        for flag in ["-n", "--dry-run", "--no-rename"] {
+            let args = CliArgs::try_parse_from(["trunc_filenames", flag, "."]).unwrap();
+            assert!(args.dry_run, "{} should enable dry-run mode", flag);
+        }
+        let args = CliArgs::try_parse_from(["trunc_filenames", "."]).unwrap();
+        assert!(!args.dry_run);
+        assert!(
+            CliArgs::try_parse_from(["trunc_filenames", "--dry-run", "--no-rename", "."]).is_err(),
+            "Aliases share one flag, so giving both is an error like repeating it"
+        );
+    }
+
+    #[test]
+    fn test_truncate_stem_word_boundaries() {
+        // Rule: With -w, cut at the last space within 10 bytes of the budget, in any script
+        let test_cases = vec![
+            // (r_stem, max_stem_bytes, expected)
+            ("this is a long name", 15, "this is a long"),
+            ("this is a long name", 9, "this is"),
+            ("a verylongwordwithoutspaces", 20, "a verylongwordwithou"),
+            ("日本語 テキスト です", 25, "日本語 テキスト"),
+            ("日本語 テキスト です", 20, "日本語 テキス"),
+            ("日本語 テキスト です", 8, "日本"),
+        ];
+        for (input, max_stem_bytes, expected) in test_cases {
+            let result = truncate_stem(input.into(), max_stem_bytes, true);
+            assert_eq!(result, OsStr::new(expected), "'{}' at {} bytes", input, max_stem_bytes);
+        }
+    }
+
+    #[test]
+    fn test_valid_utf8_prefix_len() {
+        // Rule: The prefix ends before any partial or invalid sequence
+        let test_cases: Vec<(&[u8], usize)> = vec![
+            (b"", 0),
+            (b"ascii", 5),
+            ("日本".as_bytes(), 6),
+            (&"日本".as_bytes()[..5], 3),
+            (b"ab\xFFcd", 2),
+            (b"\xFF", 0),
+        ];
+        for (input, expected) in test_cases {
+            assert_eq!(valid_utf8_prefix_len(input), expected, "Wrong prefix for {:?}", input);
+        }
+    }
 }